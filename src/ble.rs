@@ -3,65 +3,200 @@
 // https://github.com/espressif/esp-idf/blob/master/examples/bluetooth/nimble/throughput_app/blecent_throughput/main/main.c
 // https://github.com/espressif/esp-idf/blob/master/examples/bluetooth/esp_hid_host/main/esp_hid_host_main.c
 
+pub mod adv;
+pub mod channel;
 pub mod chr;
 pub mod client;
+mod conn;
 pub mod dev;
+pub mod error;
+pub mod gatt;
+pub mod gatt_std;
+mod gatt_store;
+pub mod reconnect;
 pub mod scan;
+pub mod security;
+pub mod server;
 pub mod svc;
 pub mod uuid;
 
 use self::{
-    client::BleConnectEvent,
-    dev::{BleConnHandle, BlePeerDeviceAddress},
+    channel::{BleEventSender, BleReceiver},
+    client::BleConnectionEvent,
+    dev::{BleConnHandle, BlePeerDevice, BlePeerDeviceAddress},
+    error::BleError,
+    gatt::BlePeerGatt,
+    scan::{BleAdvertisementReport, BleAdvertisementType},
+    security::{BlePasskeyAction, BlePasskeyReply, BleSecurity},
+    server::BleGattServer,
 };
-use anyhow::Result;
 use esp_idf_hal::mutex::Mutex;
 use esp_idf_svc::nvs::EspDefaultNvs;
 use std::{
     collections::HashMap,
-    sync::{mpsc::Receiver, Arc, Weak},
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, Weak,
+    },
+    time::{Duration, Instant},
 };
 
 extern "C" {
     pub fn ble_store_config_init();
+    pub fn ble_svc_gap_init();
+    pub fn ble_svc_gatt_init();
+    pub fn ble_svc_gap_device_name_set(
+        name: *const esp_idf_sys::c_types::c_char,
+    ) -> esp_idf_sys::c_types::c_int;
 }
 
 static SYNC_STATUS: Mutex<bool> = Mutex::new(false);
+static TIMEOUTS: Mutex<BleTimeouts> = Mutex::new(BleTimeouts::DEFAULT);
+
+// How long blocking operations wait for the peer before giving up.
+#[derive(Clone, Copy, Debug)]
+pub struct BleTimeouts {
+    // Includes MTU exchange and pairing.
+    pub connect: Duration,
+    pub disconnect: Duration,
+    // Each service / characteristic / descriptor discovery, read and write.
+    // Running out of time terminates the connection, so these shouldn't be
+    // shorter than the 30 second ATT timeout.
+    pub discovery: Duration,
+    pub read: Duration,
+    pub write: Duration,
+}
+
+impl BleTimeouts {
+    const DEFAULT: BleTimeouts = BleTimeouts {
+        connect: Duration::from_secs(30),
+        disconnect: Duration::from_secs(5),
+        discovery: Duration::from_secs(30),
+        read: Duration::from_secs(30),
+        write: Duration::from_secs(30),
+    };
+}
+
+impl Default for BleTimeouts {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+// Address we use when scanning, advertising and connecting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BleOwnAddressType {
+    // Public address if the controller has one, the static random one
+    // otherwise.
+    Auto,
+    Public,
+    // See Ble::set_random_address.
+    RandomStatic,
+    // Resolvable private address generated by the controller from our IRK,
+    // only bonded peers can tell it's us.
+    ResolvablePrivate,
+}
+
+// Unconnected devices that weren't seen for this long are forgotten.
+const DEFAULT_DEVICE_TTL_MS: i64 = 60_000;
 
 struct BlePeerDeviceSharedState {
     conn_handle: Option<BleConnHandle>,
     name: String,
-    callback: Option<Box<dyn FnMut(BleConnectEvent)>>,
-    event_rx: Option<Receiver<BleConnectEvent>>,
+    advertisement: BleAdvertisementReport,
+    last_seen_ms: i64,
+    connecting: bool,
+    event_rx: Option<Receiver<BleConnectionEvent>>,
+    // Instead of event_rx for connections made with BleClient::connect_async.
+    async_event_rx: Option<BleReceiver<BleConnectionEvent>>,
+    // Per characteristic value handle notification subscribers, by id.
+    subscribers: HashMap<u16, Vec<(usize, Box<dyn BleEventSender<Vec<u8>>>)>>,
+    // Attribute tree, until it changes or the device disconnects.
+    gatt: Option<BlePeerGatt>,
+    // Value handle of the peer's Service Changed characteristic.
+    service_changed_handle: Option<u16>,
+    // Of peers using resolvable private addresses, once resolved. Bonds are
+    // stored under this one.
+    identity_address: Option<BlePeerDeviceAddress>,
 }
 
 impl BlePeerDeviceSharedState {
-    pub fn new(name: String) -> Self {
+    pub fn new(advertisement: BleAdvertisementReport) -> Self {
         Self {
-            name,
+            name: advertisement.data.name.clone().unwrap_or_default(),
+            advertisement,
+            last_seen_ms: crate::get_time_millis(),
+            connecting: false,
             conn_handle: None,
-            callback: None,
             event_rx: None,
+            async_event_rx: None,
+            subscribers: HashMap::new(),
+            gatt: None,
+            service_changed_handle: None,
+            identity_address: None,
         }
     }
+
+    fn update(&mut self, advertisement: BleAdvertisementReport) {
+        self.advertisement.event_type = advertisement.event_type;
+        self.advertisement.rssi = advertisement.rssi;
+        self.advertisement.data.merge(advertisement.data);
+        if let Some(name) = &self.advertisement.data.name {
+            self.name = name.clone();
+        }
+        self.last_seen_ms = crate::get_time_millis();
+    }
+
+    fn is_in_use(&self) -> bool {
+        self.conn_handle.is_some() || self.connecting
+    }
+}
+
+pub struct BleKnownDevice {
+    pub address: BlePeerDeviceAddress,
+    pub identity_address: Option<BlePeerDeviceAddress>,
+    pub name: String,
+    pub rssi: i8,
+    // Milliseconds since boot, see crate::get_time_millis.
+    pub last_seen_ms: i64,
+    pub connected: bool,
 }
 
 pub struct Ble {
-    _default_nvs: Arc<EspDefaultNvs>,
+    default_nvs: Arc<EspDefaultNvs>,
     _self_ref: Option<Weak<Mutex<Ble>>>,
     devices: HashMap<BlePeerDeviceAddress, BlePeerDeviceSharedState>,
+    device_ttl_ms: i64,
+    own_address_type: BleOwnAddressType,
+    security: BleSecurity,
+    gatt_server: Option<BleGattServer>,
+    gatt_store: bool,
 }
 
 impl Ble {
-    pub fn new() -> Result<SafeBle> {
+    pub fn new() -> Result<SafeBle, BleError> {
         Ble::new_no_auto(Arc::new(EspDefaultNvs::new()?))
     }
 
-    pub fn new_no_auto(default_nvs: Arc<EspDefaultNvs>) -> Result<SafeBle> {
+    pub fn new_no_auto(default_nvs: Arc<EspDefaultNvs>) -> Result<SafeBle, BleError> {
+        Ble::new_with_server(default_nvs, None)
+    }
+
+    // Same as new_no_auto but also registers the services of our own GATT
+    // server, which can only be done before the host syncs.
+    pub fn new_with_server(
+        default_nvs: Arc<EspDefaultNvs>,
+        gatt_server: Option<BleGattServer>,
+    ) -> Result<SafeBle, BleError> {
         let ble = Arc::new(Mutex::new(Self {
-            _default_nvs: default_nvs,
+            default_nvs,
             _self_ref: None,
             devices: HashMap::new(),
+            device_ttl_ms: DEFAULT_DEVICE_TTL_MS,
+            own_address_type: BleOwnAddressType::Auto,
+            security: Default::default(),
+            gatt_server,
+            gatt_store: false,
         }));
         let mut locked = ble.lock();
         locked._self_ref = Some(Arc::downgrade(&ble));
@@ -69,7 +204,7 @@ impl Ble {
         Ok(SafeBle(ble.clone()))
     }
 
-    fn init(&mut self) -> Result<()> {
+    fn init(&mut self) -> Result<(), BleError> {
         unsafe {
             esp_idf_sys::esp!(esp_idf_sys::esp_nimble_hci_and_controller_init())?;
             esp_idf_sys::nimble_port_init();
@@ -78,12 +213,14 @@ impl Ble {
             esp_idf_sys::ble_hs_cfg.reset_cb = Some(Self::ble_on_reset);
             esp_idf_sys::ble_hs_cfg.sync_cb = Some(Self::ble_on_sync);
 
-            // Enable bonding.
-            esp_idf_sys::ble_hs_cfg.set_sm_bonding(1);
-            esp_idf_sys::ble_hs_cfg.sm_our_key_dist = 1;
-            esp_idf_sys::ble_hs_cfg.sm_their_key_dist = 1;
+            self.security.apply();
             ble_store_config_init();
 
+            // Register our GATT services, if any.
+            if let Some(gatt_server) = &mut self.gatt_server {
+                gatt_server.register()?;
+            }
+
             // Start the task
             esp_idf_sys::nimble_port_freertos_init(Some(Self::ble_host_task));
 
@@ -99,10 +236,217 @@ impl Ble {
         Ok(())
     }
 
+    pub fn timeouts() -> BleTimeouts {
+        *TIMEOUTS.lock()
+    }
+
+    pub fn set_timeouts(timeouts: BleTimeouts) {
+        *TIMEOUTS.lock() = timeouts;
+    }
+
     pub fn weak_ref(&mut self) -> Weak<Mutex<Self>> {
         self._self_ref.as_ref().unwrap().clone()
     }
 
+    pub fn known_devices(&self) -> Vec<BleKnownDevice> {
+        self.devices
+            .iter()
+            .map(|(address, shared)| BleKnownDevice {
+                address: address.clone(),
+                identity_address: shared.identity_address.clone(),
+                name: shared.name.clone(),
+                rssi: shared.advertisement.rssi,
+                last_seen_ms: shared.last_seen_ms,
+                connected: shared.conn_handle.is_some(),
+            })
+            .collect()
+    }
+
+    // Device with a known address, e.g. a bonded peer, even if it wasn't seen
+    // by a scan. Useful to connect without scanning first. Bonded peers using
+    // resolvable private addresses are best looked up by identity address:
+    // their private address rotates, the controller resolves the current one
+    // when connecting, see BleClient.
+    pub fn device(&mut self, address: &BlePeerDeviceAddress) -> BlePeerDevice {
+        if !self.devices.contains_key(address) {
+            let advertisement = BleAdvertisementReport {
+                event_type: BleAdvertisementType::Unknown(0),
+                // Not available.
+                rssi: 127,
+                data: Default::default(),
+            };
+            self.devices.insert(
+                address.clone(),
+                BlePeerDeviceSharedState::new(advertisement),
+            );
+        }
+        BlePeerDevice::new(address.clone(), self.weak_ref())
+    }
+
+    pub fn set_device_ttl_ms(&mut self, ttl_ms: i64) {
+        self.device_ttl_ms = ttl_ms;
+    }
+
+    // Applies to pairings started afterwards.
+    pub fn set_security(&mut self, security: BleSecurity) {
+        security.apply();
+        self.security = security;
+    }
+
+    pub fn security(&self) -> BleSecurity {
+        self.security
+    }
+
+    // Needed for io capabilities other than NoInputNoOutput.
+    pub fn set_passkey_handler(
+        &mut self,
+        handler: impl FnMut(BleConnHandle, BlePasskeyAction) -> BlePasskeyReply + Send + 'static,
+    ) {
+        security::set_passkey_handler(Some(Box::new(handler)));
+    }
+
+    // Identity addresses of the peers we have keys for.
+    pub fn bonded_peers(&self) -> Result<Vec<BlePeerDeviceAddress>, BleError> {
+        let max_peers = esp_idf_sys::CONFIG_BT_NIMBLE_MAX_BONDS as usize;
+        let mut peers = vec![esp_idf_sys::ble_addr_t::default(); max_peers];
+        let mut num_peers = 0;
+        BleError::check(unsafe {
+            esp_idf_sys::ble_store_util_bonded_peers(
+                peers.as_mut_ptr(),
+                &mut num_peers,
+                max_peers as esp_idf_sys::c_types::c_int,
+            )
+        })?;
+        peers.truncate(num_peers as usize);
+        Ok(peers.into_iter().map(BlePeerDeviceAddress).collect())
+    }
+
+    // Forgets the keys of a peer, which has to pair again on the next
+    // connection. The peer is disconnected if connected.
+    pub fn delete_bond(&mut self, address: &BlePeerDeviceAddress) -> Result<(), BleError> {
+        log::info!("BLE: deleting bond with {}", address);
+        BleError::check(unsafe { esp_idf_sys::ble_gap_unpair(&address.0) })?;
+        gatt_store::remove(self.default_nvs.clone(), address)
+    }
+
+    // Deletes every bond and stored CCCD, including our own IRK.
+    pub fn clear_bonds(&mut self) -> Result<(), BleError> {
+        log::info!("BLE: deleting all bonds");
+        let peers = self.bonded_peers()?;
+        BleError::check(unsafe { esp_idf_sys::ble_store_clear() })?;
+        for address in &peers {
+            gatt_store::remove(self.default_nvs.clone(), address)?;
+        }
+        Ok(())
+    }
+
+    // Keeps the attribute trees found by BlePeerDevice::discover_all for
+    // bonded peers in NVS, reconnecting to them doesn't need a discovery.
+    pub fn set_gatt_store(&mut self, enabled: bool) {
+        self.gatt_store = enabled;
+    }
+
+    pub(super) fn gatt_store(&self) -> Option<Arc<EspDefaultNvs>> {
+        match self.gatt_store {
+            true => Some(self.default_nvs.clone()),
+            false => None,
+        }
+    }
+
+    // Applies to scans, advertisements and connections started afterwards.
+    pub fn set_own_address_type(&mut self, address_type: BleOwnAddressType) {
+        self.own_address_type = address_type;
+    }
+
+    pub fn own_address_type(&self) -> BleOwnAddressType {
+        self.own_address_type
+    }
+
+    // Sets our static random address, most significant byte first. The two
+    // most significant bits must be set.
+    pub fn set_random_address(&mut self, address: [u8; 6]) -> Result<(), BleError> {
+        if address[0] & 0xc0 != 0xc0 {
+            return Err(BleError::Invalid(format!(
+                "static random address {:02X?}",
+                address
+            )));
+        }
+        let mut val = address;
+        val.reverse();
+        BleError::check(unsafe { esp_idf_sys::ble_hs_id_set_rnd(val.as_ptr()) })
+    }
+
+    // Generates and sets a new static random address, which is returned most
+    // significant byte first. Bonds made with the previous one are useless.
+    pub fn generate_random_address(&mut self) -> Result<[u8; 6], BleError> {
+        let mut addr: esp_idf_sys::ble_addr_t = Default::default();
+        BleError::check(unsafe { esp_idf_sys::ble_hs_id_gen_rnd(0, &mut addr) })?;
+        BleError::check(unsafe { esp_idf_sys::ble_hs_id_set_rnd(addr.val.as_ptr()) })?;
+        let mut address = addr.val;
+        address.reverse();
+        Ok(address)
+    }
+
+    // Resolves own_address_type to the value NimBLE expects.
+    pub(crate) fn own_addr_type(&self) -> Result<u8, BleError> {
+        let mut own_addr_type = 0_u8;
+        match self.own_address_type {
+            BleOwnAddressType::Auto => BleError::check(unsafe {
+                esp_idf_sys::ble_hs_id_infer_auto(0, &mut own_addr_type)
+            })?,
+            BleOwnAddressType::Public => {
+                own_addr_type = esp_idf_sys::BLE_OWN_ADDR_PUBLIC as u8;
+            }
+            BleOwnAddressType::RandomStatic => {
+                // Fails with NoAddress until one is set.
+                let mut val = [0_u8; 6];
+                BleError::check(unsafe {
+                    esp_idf_sys::ble_hs_id_copy_addr(
+                        esp_idf_sys::BLE_ADDR_RANDOM as u8,
+                        val.as_mut_ptr(),
+                        std::ptr::null_mut(),
+                    )
+                })?;
+                own_addr_type = esp_idf_sys::BLE_OWN_ADDR_RANDOM as u8;
+            }
+            // Picks the RPA variant matching our identity address.
+            BleOwnAddressType::ResolvablePrivate => BleError::check(unsafe {
+                esp_idf_sys::ble_hs_id_infer_auto(1, &mut own_addr_type)
+            })?,
+        }
+        Ok(own_addr_type)
+    }
+
+    // Registers a discovered device or updates the one we already know, so
+    // connection state isn't lost. Returns the merged advertisement data.
+    fn update_device(
+        &mut self,
+        address: &BlePeerDeviceAddress,
+        advertisement: BleAdvertisementReport,
+    ) -> BleAdvertisementReport {
+        self.evict_stale_devices();
+        match self.devices.get_mut(address) {
+            Some(shared) => {
+                shared.update(advertisement);
+                shared.advertisement.clone()
+            }
+            None => {
+                self.devices.insert(
+                    address.clone(),
+                    BlePeerDeviceSharedState::new(advertisement.clone()),
+                );
+                advertisement
+            }
+        }
+    }
+
+    fn evict_stale_devices(&mut self) {
+        let now = crate::get_time_millis();
+        let ttl_ms = self.device_ttl_ms;
+        self.devices
+            .retain(|_, shared| shared.is_in_use() || now - shared.last_seen_ms < ttl_ms);
+    }
+
     unsafe extern "C" fn ble_on_reset(reason: esp_idf_sys::c_types::c_int) {
         log::error!("BLE on reset, reason code: {}", reason);
     }
@@ -113,41 +457,6 @@ impl Ble {
         *sync = true;
     }
 
-    // unsafe extern "C" fn ble_on_read(
-    //     obj_type: esp_idf_sys::c_types::c_int,
-    //     key: *const esp_idf_sys::ble_store_key,
-    //     dst: *mut esp_idf_sys::ble_store_value,
-    // ) -> esp_idf_sys::c_types::c_int {
-    //     log::debug!(
-    //         "BLE on read: obj_type={} key.sec={:?} key.cccd={:?}",
-    //         obj_type,
-    //         (*key).sec,
-    //         (*key).cccd
-    //     );
-    //     esp_idf_sys::BLE_HS_ENOENT.try_into().unwrap()
-    // }
-
-    // unsafe extern "C" fn ble_on_write(
-    //     obj_type: esp_idf_sys::c_types::c_int,
-    //     val: *const esp_idf_sys::ble_store_value,
-    // ) -> esp_idf_sys::c_types::c_int {
-    //     log::debug!(
-    //         "BLE on write: obj_type={} val.sec={:?} val.cccd={:?}",
-    //         obj_type,
-    //         (*val).sec,
-    //         (*val).cccd
-    //     );
-    //     0
-    // }
-
-    // unsafe extern "C" fn ble_on_delete(
-    //     obj_type: esp_idf_sys::c_types::c_int,
-    //     key: *const esp_idf_sys::ble_store_key,
-    // ) -> esp_idf_sys::c_types::c_int {
-    //     log::debug!("BLE on delete");
-    //     0
-    // }
-
     unsafe extern "C" fn ble_host_task(_params: *mut esp_idf_sys::c_types::c_void) {
         log::info!("BLE host task started");
         esp_idf_sys::nimble_port_run(); //This function will return only when nimble_port_stop() is executed.
@@ -178,6 +487,99 @@ impl Drop for Ble {
 
 unsafe impl Send for Ble {}
 
+// GATT procedures report back through a channel whose sender is handed over to
+// NimBLE as cb_arg. The FFI callback releases it on the last call of the
+// procedure, so callers that stop waiting (or drop the future) don't leave a
+// dangling pointer behind.
+pub(crate) fn procedure_cb_arg<T>(
+    tx: impl BleEventSender<T> + 'static,
+) -> *mut esp_idf_sys::c_types::c_void {
+    let tx: Box<dyn BleEventSender<T>> = Box::new(tx);
+    Box::into_raw(Box::new(tx)) as *mut esp_idf_sys::c_types::c_void
+}
+
+pub(crate) unsafe fn procedure_send<T>(cb_arg: *mut esp_idf_sys::c_types::c_void, event: T) {
+    // The receiver is gone if the caller timed out.
+    (*(cb_arg as *const Box<dyn BleEventSender<T>>)).send_event(event);
+}
+
+pub(crate) unsafe fn procedure_release<T>(cb_arg: *mut esp_idf_sys::c_types::c_void) {
+    drop(Box::from_raw(cb_arg as *mut Box<dyn BleEventSender<T>>));
+}
+
+// Waits for the next procedure event. On timeout the connection is terminated,
+// the only way to make NimBLE abort pending GATT procedures. The ATT spec
+// mandates it once a request goes unanswered for 30 seconds, which is why the
+// GATT timeouts default to that.
+pub(crate) fn procedure_recv<T>(
+    rx: &Receiver<T>,
+    deadline: Instant,
+    conn_handle: BleConnHandle,
+    operation: &'static str,
+) -> Result<T, BleError> {
+    match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(event) => Ok(event),
+        Err(RecvTimeoutError::Timeout) => {
+            log::error!(
+                "BLE {}: timeout, terminating conn_handle={}",
+                operation,
+                conn_handle
+            );
+            unsafe {
+                esp_idf_sys::ble_gap_terminate(
+                    conn_handle as u16,
+                    esp_idf_sys::BLE_ERR_REM_USER_CONN_TERM as u8,
+                )
+            };
+            Err(BleError::Timeout(operation))
+        }
+        Err(RecvTimeoutError::Disconnected) => Err(BleError::Aborted(operation)),
+    }
+}
+
+// Async counterpart of procedure_recv. There's no deadline, NimBLE fails
+// procedures the peer doesn't answer after 30 seconds, and dropping the
+// future is always safe.
+pub(crate) async fn procedure_next<T>(
+    rx: &mut BleReceiver<T>,
+    operation: &'static str,
+) -> Result<T, BleError> {
+    rx.recv().await.ok_or(BleError::Aborted(operation))
+}
+
+// Milliseconds to the 0.625ms units of scan and advertising intervals, within
+// the range (in units) allowed by the spec.
+pub(crate) fn interval_units(
+    what: &str,
+    ms: u32,
+    range: std::ops::RangeInclusive<u32>,
+) -> Result<u16, BleError> {
+    match ms.checked_mul(1000).map(|us| us / 625) {
+        Some(units) if range.contains(&units) => Ok(units as u16),
+        _ => Err(BleError::Invalid(format!(
+            "{} {}ms, must be {}ms to {}ms",
+            what,
+            ms,
+            range.start() * 625 / 1000,
+            range.end() * 625 / 1000
+        ))),
+    }
+}
+
+// Copies the contents of an mbuf chain into a Vec. The mbuf is still owned by
+// NimBLE.
+pub(crate) unsafe fn os_mbuf_to_vec(mut om: *const esp_idf_sys::os_mbuf) -> Vec<u8> {
+    let mut data = vec![];
+    while !om.is_null() {
+        data.extend_from_slice(std::slice::from_raw_parts(
+            (*om).om_data,
+            (*om).om_len as usize,
+        ));
+        om = (*om).om_next.sle_next;
+    }
+    data
+}
+
 #[derive(Clone)]
 pub struct SafeBle(Arc<Mutex<Ble>>);
 
@@ -0,0 +1,485 @@
+// https://github.com/espressif/esp-idf/blob/master/examples/bluetooth/nimble/bleprph/main/main.c
+// https://github.com/espressif/esp-idf/blob/master/examples/bluetooth/nimble/ibeacon/main/main.c
+
+use super::{dev::BleConnHandle, error::BleError, uuid::BleUUID, SafeBle};
+use esp_idf_hal::mutex::Mutex;
+use esp_idf_sys::{BLE_UUID_TYPE_128, BLE_UUID_TYPE_16, BLE_UUID_TYPE_32};
+use std::sync::mpsc::{Receiver, Sender};
+
+// Fields of an advertisement or scan response packet. Both packets are
+// limited to 31 bytes, so not everything fits at once.
+#[derive(Clone, Default)]
+pub struct BleAdvertisementData {
+    pub name: Option<String>,
+    pub service_uuids: Vec<BleUUID>,
+    // Company identifier (little endian) followed by the payload.
+    pub manufacturer_data: Option<Vec<u8>>,
+    pub tx_power: Option<i8>,
+    pub appearance: Option<u16>,
+}
+
+impl BleAdvertisementData {
+    // Shortened names are reported as the name too.
+    pub(super) unsafe fn from_native(fields: &esp_idf_sys::ble_hs_adv_fields) -> Self {
+        let mut service_uuids = vec![];
+        for i in 0..fields.num_uuids16 as usize {
+            service_uuids.push(BleUUID::from(esp_idf_sys::ble_uuid_any_t {
+                u16_: *fields.uuids16.add(i),
+            }));
+        }
+        for i in 0..fields.num_uuids32 as usize {
+            service_uuids.push(BleUUID::from(esp_idf_sys::ble_uuid_any_t {
+                u32_: *fields.uuids32.add(i),
+            }));
+        }
+        for i in 0..fields.num_uuids128 as usize {
+            service_uuids.push(BleUUID::from(esp_idf_sys::ble_uuid_any_t {
+                u128_: *fields.uuids128.add(i),
+            }));
+        }
+        Self {
+            name: if fields.name.is_null() {
+                None
+            } else {
+                Some(
+                    String::from_utf8_lossy(std::slice::from_raw_parts(
+                        fields.name,
+                        fields.name_len as usize,
+                    ))
+                    .into_owned(),
+                )
+            },
+            service_uuids,
+            manufacturer_data: if fields.mfg_data.is_null() {
+                None
+            } else {
+                Some(
+                    std::slice::from_raw_parts(fields.mfg_data, fields.mfg_data_len as usize)
+                        .to_vec(),
+                )
+            },
+            tx_power: match fields.tx_pwr_lvl_is_present() {
+                1 => Some(fields.tx_pwr_lvl),
+                _ => None,
+            },
+            appearance: match fields.appearance_is_present() {
+                1 => Some(fields.appearance),
+                _ => None,
+            },
+        }
+    }
+
+    // Scan responses only carry part of the data, so fields missing from
+    // other are kept.
+    pub(super) fn merge(&mut self, other: BleAdvertisementData) {
+        if other.name.is_some() {
+            self.name = other.name;
+        }
+        if !other.service_uuids.is_empty() {
+            self.service_uuids = other.service_uuids;
+        }
+        if other.manufacturer_data.is_some() {
+            self.manufacturer_data = other.manufacturer_data;
+        }
+        if other.tx_power.is_some() {
+            self.tx_power = other.tx_power;
+        }
+        if other.appearance.is_some() {
+            self.appearance = other.appearance;
+        }
+    }
+
+    // Calls f with the NimBLE representation of the data. The pointers inside
+    // ble_hs_adv_fields are only valid during the call.
+    fn with_native<T>(&self, flags: u8, f: impl FnOnce(&esp_idf_sys::ble_hs_adv_fields) -> T) -> T {
+        let mut uuids16 = vec![];
+        let mut uuids32 = vec![];
+        let mut uuids128 = vec![];
+        for uuid in &self.service_uuids {
+            unsafe {
+                let native = uuid.native();
+                match native.u.type_ as u32 {
+                    BLE_UUID_TYPE_16 => uuids16.push(native.u16_),
+                    BLE_UUID_TYPE_32 => uuids32.push(native.u32_),
+                    BLE_UUID_TYPE_128 => uuids128.push(native.u128_),
+                    _ => {}
+                }
+            }
+        }
+
+        let mut fields = esp_idf_sys::ble_hs_adv_fields {
+            flags,
+            ..Default::default()
+        };
+        if !uuids16.is_empty() {
+            fields.uuids16 = uuids16.as_ptr();
+            fields.num_uuids16 = uuids16.len() as u8;
+            fields.set_uuids16_is_complete(1);
+        }
+        if !uuids32.is_empty() {
+            fields.uuids32 = uuids32.as_ptr();
+            fields.num_uuids32 = uuids32.len() as u8;
+            fields.set_uuids32_is_complete(1);
+        }
+        if !uuids128.is_empty() {
+            fields.uuids128 = uuids128.as_ptr();
+            fields.num_uuids128 = uuids128.len() as u8;
+            fields.set_uuids128_is_complete(1);
+        }
+        if let Some(name) = &self.name {
+            fields.name = name.as_ptr();
+            fields.name_len = name.len() as u8;
+            fields.set_name_is_complete(1);
+        }
+        if let Some(tx_power) = self.tx_power {
+            fields.tx_pwr_lvl = tx_power;
+            fields.set_tx_pwr_lvl_is_present(1);
+        }
+        if let Some(appearance) = self.appearance {
+            fields.appearance = appearance;
+            fields.set_appearance_is_present(1);
+        }
+        if let Some(mfg_data) = &self.manufacturer_data {
+            fields.mfg_data = mfg_data.as_ptr();
+            fields.mfg_data_len = mfg_data.len() as u8;
+        }
+
+        f(&fields)
+    }
+}
+
+pub enum BleAdvertiserEvent {
+    // A central connected to us, advertising stops until started again.
+    Connected(BleConnHandle),
+    Disconnected(BleConnHandle),
+    // Advertising stopped on its own (e.g. timeout).
+    Finished,
+    // Negotiated ATT MTU.
+    MtuChanged(BleConnHandle, u16),
+    // Pairing, or restoring the keys of a bond, finished.
+    EncryptionChanged(BleConnHandle, Result<(), BleError>),
+    // The central changed its subscription to one of our characteristics, see
+    // BleGattServer.
+    Subscribed {
+        conn_handle: BleConnHandle,
+        attr_handle: u16,
+        notify: bool,
+        indicate: bool,
+    },
+}
+
+// Advertising sessions, and the connections they accepted, NimBLE reports to.
+// NimBLE gets the session id as cb_arg and keeps using it for the accepted
+// connection, so an entry lives until advertising ends without a connection,
+// or until the connection it accepted is over. Stopping the advertiser or
+// dropping it doesn't affect accepted connections.
+static SESSIONS: Mutex<BleAdvertiserSessions> = Mutex::new(BleAdvertiserSessions {
+    next_id: 1,
+    entries: Vec::new(),
+});
+
+struct BleAdvertiserSessions {
+    next_id: usize,
+    entries: Vec<BleAdvertiserSession>,
+}
+
+struct BleAdvertiserSession {
+    id: usize,
+    // Set once a central connected, advertising is over by then.
+    conn_handle: Option<BleConnHandle>,
+    adv_tx: Sender<BleAdvertiserEvent>,
+}
+
+impl BleAdvertiserSessions {
+    fn get(&self, id: usize) -> Option<&BleAdvertiserSession> {
+        self.entries.iter().find(|session| session.id == id)
+    }
+
+    // Started and neither stopped nor connected yet.
+    fn advertising(&self, id: usize) -> bool {
+        matches!(self.get(id), Some(session) if session.conn_handle.is_none())
+    }
+
+    fn send(&self, id: usize, event: BleAdvertiserEvent) {
+        match self.get(id) {
+            // Nobody might be listening anymore, that's fine.
+            Some(session) => drop(session.adv_tx.send(event)),
+            None => log::warn!("BLE advertiser: event for unknown session id={}", id),
+        }
+    }
+
+    fn remove(&mut self, id: usize) {
+        self.entries.retain(|session| session.id != id);
+    }
+}
+
+// Legacy advertising, in 0.625ms units.
+const ADV_INTERVAL_RANGE: std::ops::RangeInclusive<u32> = 0x0020..=0x4000;
+
+pub struct BleAdvertiser {
+    ble: SafeBle,
+    adv_params: esp_idf_sys::ble_gap_adv_params,
+    data: BleAdvertisementData,
+    scan_response: Option<BleAdvertisementData>,
+    // Id of the last session started, see SESSIONS.
+    session_id: Option<usize>,
+    adv_tx: Sender<BleAdvertiserEvent>,
+    adv_rx: Receiver<BleAdvertiserEvent>,
+}
+
+impl BleAdvertiser {
+    pub fn new(ble: SafeBle) -> Self {
+        let (adv_tx, adv_rx) = std::sync::mpsc::channel();
+        let mut adv = Self {
+            ble,
+            adv_params: esp_idf_sys::ble_gap_adv_params {
+                ..Default::default()
+            },
+            data: Default::default(),
+            scan_response: None,
+            session_id: None,
+            adv_tx,
+            adv_rx,
+        };
+        adv.set_connectable(true);
+        adv
+    }
+
+    pub fn set_data(&mut self, data: BleAdvertisementData) -> &mut Self {
+        self.data = data;
+        self
+    }
+
+    pub fn set_scan_response(&mut self, data: Option<BleAdvertisementData>) -> &mut Self {
+        self.scan_response = data;
+        self
+    }
+
+    // Non-connectable advertising is meant for beacons.
+    pub fn set_connectable(&mut self, connectable: bool) -> &mut Self {
+        self.adv_params.conn_mode = match connectable {
+            true => esp_idf_sys::BLE_GAP_CONN_MODE_UND,
+            false => esp_idf_sys::BLE_GAP_CONN_MODE_NON,
+        } as u8;
+        self.adv_params.disc_mode = esp_idf_sys::BLE_GAP_DISC_MODE_GEN as u8;
+        self
+    }
+
+    // Advertising interval range in milliseconds, 20ms to 10.24s. Zero for
+    // both means the NimBLE default.
+    pub fn set_interval(&mut self, min_ms: u32, max_ms: u32) -> Result<&mut Self, BleError> {
+        let (min, max) = match (min_ms, max_ms) {
+            (0, 0) => (0, 0),
+            _ => (
+                super::interval_units("advertising interval", min_ms, ADV_INTERVAL_RANGE)?,
+                super::interval_units("advertising interval", max_ms, ADV_INTERVAL_RANGE)?,
+            ),
+        };
+        if min > max {
+            return Err(BleError::Invalid(format!(
+                "advertising interval {}ms to {}ms",
+                min_ms, max_ms
+            )));
+        }
+        self.adv_params.itvl_min = min;
+        self.adv_params.itvl_max = max;
+        Ok(self)
+    }
+
+    pub fn start(&mut self) -> Result<&Receiver<BleAdvertiserEvent>, BleError> {
+        let own_addr_type = self.ble.lock().own_addr_type()?;
+
+        // The GAP service reports the same name we advertise.
+        if let Some(name) = &self.data.name {
+            let name = std::ffi::CString::new(name.as_str())
+                .map_err(|_| BleError::Invalid(format!("device name {:?}", name)))?;
+            BleError::check(unsafe { super::ble_svc_gap_device_name_set(name.as_ptr()) })?;
+        }
+
+        let flags =
+            (esp_idf_sys::BLE_HS_ADV_F_DISC_GEN | esp_idf_sys::BLE_HS_ADV_F_BREDR_UNSUP) as u8;
+        BleError::check(self.data.with_native(flags, |fields| unsafe {
+            esp_idf_sys::ble_gap_adv_set_fields(fields)
+        }))?;
+        match &self.scan_response {
+            Some(scan_response) => {
+                BleError::check(scan_response.with_native(0, |fields| unsafe {
+                    esp_idf_sys::ble_gap_adv_rsp_set_fields(fields)
+                }))?
+            }
+            // Clears the scan response of a previous start.
+            None => BleError::check(unsafe {
+                esp_idf_sys::ble_gap_adv_rsp_set_data(std::ptr::null(), 0)
+            })?,
+        }
+
+        // Held until the session is in place, so the first events wait for
+        // it. NimBLE never calls back from ble_gap_adv_start itself.
+        let mut sessions = SESSIONS.lock();
+        let id = sessions.next_id;
+        // 0 would be a null cb_arg.
+        sessions.next_id = sessions.next_id.checked_add(1).unwrap_or(1);
+        BleError::check(unsafe {
+            esp_idf_sys::ble_gap_adv_start(
+                own_addr_type,
+                std::ptr::null(),
+                i32::MAX,
+                &self.adv_params,
+                Some(BleAdvertiser::ble_on_gap_adv_event),
+                id as *mut esp_idf_sys::c_types::c_void,
+            )
+        })?;
+        sessions.entries.push(BleAdvertiserSession {
+            id,
+            conn_handle: None,
+            adv_tx: self.adv_tx.clone(),
+        });
+        self.session_id = Some(id);
+
+        Ok(&self.adv_rx)
+    }
+
+    // Does nothing if advertising already ended, connections it accepted are
+    // left alone.
+    pub fn stop(&mut self) -> Result<(), BleError> {
+        let mut sessions = SESSIONS.lock();
+        let id = match self.session_id {
+            Some(id) if sessions.advertising(id) => id,
+            _ => return Ok(()),
+        };
+        match BleError::check(unsafe { esp_idf_sys::ble_gap_adv_stop() }) {
+            // No more events for this session.
+            Ok(()) => {
+                sessions.remove(id);
+                self.session_id = None;
+                Ok(())
+            }
+            // Just ended, the CONNECT or ADV_COMPLETE event waiting for the
+            // lock takes care of the session.
+            Err(BleError::Already) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn is_advertising(&self) -> bool {
+        matches!(self.session_id, Some(id) if SESSIONS.lock().advertising(id))
+    }
+
+    unsafe extern "C" fn ble_on_gap_adv_event(
+        event: *mut esp_idf_sys::ble_gap_event,
+        cb_arg: *mut esp_idf_sys::c_types::c_void,
+    ) -> esp_idf_sys::c_types::c_int {
+        let event = *event;
+        let id = cb_arg as usize;
+
+        match event.type_ as u32 {
+            esp_idf_sys::BLE_GAP_EVENT_CONNECT => {
+                log::info!("BLE gap event, BLE_GAP_EVENT_CONNECT (advertiser)");
+                let mut sessions = SESSIONS.lock();
+                if event.__bindgen_anon_1.connect.status == 0 {
+                    let conn_handle = event.__bindgen_anon_1.connect.conn_handle as BleConnHandle;
+                    if let Some(session) =
+                        sessions.entries.iter_mut().find(|session| session.id == id)
+                    {
+                        session.conn_handle = Some(conn_handle);
+                    }
+                    sessions.send(id, BleAdvertiserEvent::Connected(conn_handle));
+                } else {
+                    // Connection failed, advertising stopped.
+                    sessions.send(id, BleAdvertiserEvent::Finished);
+                    sessions.remove(id);
+                }
+                0
+            }
+
+            esp_idf_sys::BLE_GAP_EVENT_DISCONNECT => {
+                log::info!("BLE gap event, BLE_GAP_EVENT_DISCONNECT (advertiser)");
+                let mut sessions = SESSIONS.lock();
+                sessions.send(
+                    id,
+                    BleAdvertiserEvent::Disconnected(
+                        event.__bindgen_anon_1.disconnect.conn.conn_handle as BleConnHandle,
+                    ),
+                );
+                // Last event of the session.
+                sessions.remove(id);
+                0
+            }
+
+            esp_idf_sys::BLE_GAP_EVENT_MTU => {
+                log::info!("BLE gap event, BLE_GAP_EVENT_MTU (advertiser)");
+                SESSIONS.lock().send(
+                    id,
+                    BleAdvertiserEvent::MtuChanged(
+                        event.__bindgen_anon_1.mtu.conn_handle as BleConnHandle,
+                        event.__bindgen_anon_1.mtu.value,
+                    ),
+                );
+                0
+            }
+
+            esp_idf_sys::BLE_GAP_EVENT_ENC_CHANGE => {
+                log::info!("BLE gap event, BLE_GAP_EVENT_ENC_CHANGE (advertiser)");
+                let conn_handle = event.__bindgen_anon_1.enc_change.conn_handle as BleConnHandle;
+                let status = BleError::check(event.__bindgen_anon_1.enc_change.status);
+                if let Err(e) = &status {
+                    log::error!("BLE advertiser: encryption failed: {}", e);
+                }
+                SESSIONS.lock().send(
+                    id,
+                    BleAdvertiserEvent::EncryptionChanged(conn_handle, status),
+                );
+                0
+            }
+
+            esp_idf_sys::BLE_GAP_EVENT_SUBSCRIBE => {
+                log::info!("BLE gap event, BLE_GAP_EVENT_SUBSCRIBE (advertiser)");
+                let subscribe = event.__bindgen_anon_1.subscribe;
+                SESSIONS.lock().send(
+                    id,
+                    BleAdvertiserEvent::Subscribed {
+                        conn_handle: subscribe.conn_handle as BleConnHandle,
+                        attr_handle: subscribe.attr_handle,
+                        notify: subscribe.cur_notify() == 1,
+                        indicate: subscribe.cur_indicate() == 1,
+                    },
+                );
+                0
+            }
+
+            esp_idf_sys::BLE_GAP_EVENT_PASSKEY_ACTION => {
+                log::info!("BLE gap event, BLE_GAP_EVENT_PASSKEY_ACTION (advertiser)");
+                super::security::on_passkey_action(
+                    event.__bindgen_anon_1.passkey.conn_handle,
+                    &event.__bindgen_anon_1.passkey.params,
+                );
+                0
+            }
+
+            esp_idf_sys::BLE_GAP_EVENT_REPEAT_PAIRING => {
+                log::info!("BLE gap event, BLE_GAP_EVENT_REPEAT_PAIRING (advertiser)");
+                super::security::on_repeat_pairing(
+                    event.__bindgen_anon_1.repeat_pairing.conn_handle,
+                )
+            }
+
+            esp_idf_sys::BLE_GAP_EVENT_ADV_COMPLETE => {
+                log::info!("BLE gap event, BLE_GAP_EVENT_ADV_COMPLETE");
+                // Ended without a connection (legacy advertising).
+                let mut sessions = SESSIONS.lock();
+                sessions.send(id, BleAdvertiserEvent::Finished);
+                sessions.remove(id);
+                0
+            }
+
+            _ => 0,
+        }
+    }
+}
+
+impl Drop for BleAdvertiser {
+    fn drop(&mut self) {
+        log::info!("BleAdvertiser dropping ...");
+        self.stop().ok();
+    }
+}
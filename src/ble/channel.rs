@@ -0,0 +1,134 @@
+// Channel whose receiver can be awaited. NimBLE callbacks push events from the
+// host task and wake whatever executor is polling the receiver, so async code
+// doesn't need a thread per peer.
+
+use esp_idf_hal::mutex::Mutex;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+struct BleChannelState<T> {
+    queue: VecDeque<T>,
+    waker: Option<Waker>,
+    senders: usize,
+    receiver: bool,
+}
+
+pub struct BleSender<T>(Arc<Mutex<BleChannelState<T>>>);
+
+pub struct BleReceiver<T>(Arc<Mutex<BleChannelState<T>>>);
+
+pub fn channel<T>() -> (BleSender<T>, BleReceiver<T>) {
+    let state = Arc::new(Mutex::new(BleChannelState {
+        queue: VecDeque::new(),
+        waker: None,
+        senders: 1,
+        receiver: true,
+    }));
+    (BleSender(state.clone()), BleReceiver(state))
+}
+
+impl<T> BleSender<T> {
+    // False if the receiver is gone.
+    pub fn send(&self, value: T) -> bool {
+        let waker = {
+            let mut state = self.0.lock();
+            if !state.receiver {
+                return false;
+            }
+            state.queue.push_back(value);
+            state.waker.take()
+        };
+        // Woken without the lock held, the executor might poll right away.
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        true
+    }
+}
+
+impl<T> Clone for BleSender<T> {
+    fn clone(&self) -> Self {
+        self.0.lock().senders += 1;
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Drop for BleSender<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut state = self.0.lock();
+            state.senders -= 1;
+            match state.senders {
+                0 => state.waker.take(),
+                _ => None,
+            }
+        };
+        // Let the receiver know the channel is closed.
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> BleReceiver<T> {
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.0.lock().queue.pop_front()
+    }
+
+    // Ready with None once every sender is gone and the queue is empty.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut state = self.0.lock();
+        if let Some(value) = state.queue.pop_front() {
+            return Poll::Ready(Some(value));
+        }
+        if state.senders == 0 {
+            return Poll::Ready(None);
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    pub fn recv(&mut self) -> BleRecv<'_, T> {
+        BleRecv(self)
+    }
+}
+
+impl<T> Drop for BleReceiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.receiver = false;
+        state.queue.clear();
+    }
+}
+
+pub struct BleRecv<'a, T>(&'a mut BleReceiver<T>);
+
+impl<'a, T> Future for BleRecv<'a, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().0.poll_recv(cx)
+    }
+}
+
+// Where FFI callbacks deliver events, either to blocking code (mpsc) or to
+// async code (BleSender).
+pub(crate) trait BleEventSender<T> {
+    // False if nobody is listening anymore.
+    fn send_event(&self, event: T) -> bool;
+}
+
+impl<T> BleEventSender<T> for std::sync::mpsc::Sender<T> {
+    fn send_event(&self, event: T) -> bool {
+        self.send(event).is_ok()
+    }
+}
+
+impl<T> BleEventSender<T> for BleSender<T> {
+    fn send_event(&self, event: T) -> bool {
+        self.send(event)
+    }
+}
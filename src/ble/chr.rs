@@ -1,11 +1,23 @@
-use super::{dev::BleConnHandle, uuid::BleUUID};
-use anyhow::Result;
-
+use super::channel::{BleEventSender, BleReceiver};
+use super::gatt_std::{CCCD_UUID, CHR_DECLARATION_UUID};
+use super::{dev::BleConnHandle, error::BleError, uuid::BleUUID, Ble};
+use esp_idf_hal::mutex::Mutex;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    mpsc::Receiver,
+    Weak,
+};
+use std::time::Instant;
+
+// Ids of the subscribers added by add_subscriber.
+static NEXT_SUBSCRIBER_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(Clone)]
 pub struct BlePeerDescriptor {
-    conn_handle: BleConnHandle,
-    chr_val_handle: u16,
-    handle: u16,
-    uuid: BleUUID,
+    pub(super) conn_handle: BleConnHandle,
+    pub(super) chr_val_handle: u16,
+    pub(super) handle: u16,
+    pub(super) uuid: BleUUID,
 }
 impl std::fmt::Display for BlePeerDescriptor {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -24,19 +36,52 @@ impl BlePeerDescriptor {
     pub fn uuid(&self) -> &BleUUID {
         &self.uuid
     }
-    fn write(&self, data: [u8; 1]) -> Result<()> {
-        write(self.conn_handle, self.handle, &data)
+    fn write(&self, data: &[u8]) -> Result<(), BleError> {
+        write(self.conn_handle, self.handle, data)
     }
-    pub fn write_no_response(&self, data: [u8; 1]) -> Result<()> {
+    pub fn write_no_response(&self, data: [u8; 1]) -> Result<(), BleError> {
         write_no_response(self.conn_handle, self.handle, &data)
     }
+    pub fn read(&self) -> Result<Vec<u8>, BleError> {
+        read(self.conn_handle, self.handle)
+    }
+    async fn write_async(&self, data: &[u8]) -> Result<(), BleError> {
+        write_async(self.conn_handle, self.handle, data).await
+    }
+    pub async fn read_async(&self) -> Result<Vec<u8>, BleError> {
+        read_async(self.conn_handle, self.handle).await
+    }
 }
 
 enum BlePeerDescriptorDiscoveryEvent {
     Discovery(BlePeerDescriptor),
-    DiscoveryFinished,
+    DiscoveryFinished(u16),
 }
 
+// Value written to the Client Characteristic Configuration Descriptor (0x2902)
+// of a characteristic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BleSubscription {
+    None,
+    // Push without acknowledgement.
+    Notify,
+    // Push acknowledged by the client (NimBLE sends the confirmation).
+    Indicate,
+    Both,
+}
+
+impl BleSubscription {
+    fn cccd_value(&self) -> [u8; 2] {
+        match self {
+            Self::None => [0, 0],
+            Self::Notify => [1, 0],
+            Self::Indicate => [2, 0],
+            Self::Both => [3, 0],
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct BlePeerCharacteristic {
     pub(super) conn_handle: BleConnHandle,
     pub(super) def_handle: u16,
@@ -44,6 +89,7 @@ pub struct BlePeerCharacteristic {
     pub(super) end_handle: u16,
     pub(super) properties: u8,
     pub(super) uuid: BleUUID,
+    pub(super) ble: Weak<Mutex<Ble>>,
     // pub (super) service: BleService,
 }
 
@@ -76,103 +122,322 @@ impl BlePeerCharacteristic {
         return (self.properties & esp_idf_sys::BLE_GATT_CHR_PROP_WRITE_NO_RSP as u8) != 0;
     }
 
-    pub fn write(&self, data: &[u8]) -> Result<()> {
+    pub fn read(&self) -> Result<Vec<u8>, BleError> {
+        if !self.can_read() {
+            return Err(BleError::Unsupported("characteristic reads"));
+        }
+        read(self.conn_handle, self.val_handle)
+    }
+
+    // Same as read but uses the "read long" procedure, needed for values that
+    // don't fit in a single ATT_MTU.
+    pub fn read_long(&self) -> Result<Vec<u8>, BleError> {
+        if !self.can_read() {
+            return Err(BleError::Unsupported("characteristic reads"));
+        }
+        read_long(self.conn_handle, self.val_handle)
+    }
+
+    // Up to 512 bytes, values that don't fit in the MTU are sent with a long
+    // write.
+    pub fn write(&self, data: &[u8]) -> Result<(), BleError> {
         if !self.can_write() {
-            anyhow::bail!("BLE chr: characteristic doesn't support writes");
+            return Err(BleError::Unsupported("characteristic writes"));
         }
         write(self.conn_handle as u32, self.val_handle, data)
     }
 
-    pub fn write_no_response(&self, data: &[u8]) -> Result<()> {
+    pub async fn read_async(&self) -> Result<Vec<u8>, BleError> {
+        if !self.can_read() {
+            return Err(BleError::Unsupported("characteristic reads"));
+        }
+        read_async(self.conn_handle, self.val_handle).await
+    }
+
+    pub async fn read_long_async(&self) -> Result<Vec<u8>, BleError> {
+        if !self.can_read() {
+            return Err(BleError::Unsupported("characteristic reads"));
+        }
+        read_long_async(self.conn_handle, self.val_handle).await
+    }
+
+    pub async fn write_async(&self, data: &[u8]) -> Result<(), BleError> {
+        if !self.can_write() {
+            return Err(BleError::Unsupported("characteristic writes"));
+        }
+        write_async(self.conn_handle, self.val_handle, data).await
+    }
+
+    pub fn write_no_response(&self, data: &[u8]) -> Result<(), BleError> {
         if !self.can_write_no_response() {
-            anyhow::bail!("BLE chr: characteristic doesn't support writes without response");
+            return Err(BleError::Unsupported(
+                "characteristic writes without response",
+            ));
         }
         write_no_response(self.conn_handle, self.val_handle, data)
     }
 
-    pub fn get_descriptor_by_uuid(&self, uuid: &BleUUID) -> Result<Option<BlePeerDescriptor>> {
+    pub fn get_descriptor_by_uuid(
+        &self,
+        uuid: &BleUUID,
+    ) -> Result<Option<BlePeerDescriptor>, BleError> {
         let descriptors = self.get_descriptors()?;
-        let dsc = match descriptors.into_iter().find(|dsc| dsc.uuid() == uuid) {
-            Some(dsc) => dsc,
-            None => return Ok(None),
-        };
-        Ok(Some(dsc))
+        Ok(descriptors.into_iter().find(|dsc| dsc.uuid() == uuid))
     }
 
-    pub fn get_descriptors(&self) -> Result<Vec<BlePeerDescriptor>> {
-        log::info!("Retrieving descriptors for service {}", self);
+    pub async fn get_descriptor_by_uuid_async(
+        &self,
+        uuid: &BleUUID,
+    ) -> Result<Option<BlePeerDescriptor>, BleError> {
+        let descriptors = self.get_descriptors_async().await?;
+        Ok(descriptors.into_iter().find(|dsc| dsc.uuid() == uuid))
+    }
 
+    pub fn get_descriptors(&self) -> Result<Vec<BlePeerDescriptor>, BleError> {
+        let deadline = Instant::now() + Ble::timeouts().discovery;
+
+        // Start the discovery, results are sent back through a channel.
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.start_descriptor_discovery(tx)?;
+
+        // Wait for results.
         let mut descriptors = vec![];
-        {
-            // Callback.
-            let (tx, rx) = std::sync::mpsc::channel();
-            let descriptors = &mut descriptors;
-            let mut callback: Box<dyn FnMut(BlePeerDescriptorDiscoveryEvent)> =
-                Box::new(move |event| match event {
-                    BlePeerDescriptorDiscoveryEvent::Discovery(dsc) => {
-                        log::info!("Found: {}", dsc);
-                        descriptors.push(dsc);
-                    }
-                    BlePeerDescriptorDiscoveryEvent::DiscoveryFinished => tx.send(()).unwrap(),
-                });
-
-            // Start the discovery thread.
-            let cb_arg: *mut _ = &mut callback;
-            let rc = unsafe {
-                esp_idf_sys::ble_gattc_disc_all_dscs(
-                    self.conn_handle as u16,
-                    self.val_handle,
-                    self.end_handle,
-                    Some(BlePeerCharacteristic::ble_on_gatt_disc_dscs),
-                    cb_arg as *mut esp_idf_sys::c_types::c_void,
-                )
-            };
-            if rc != 0 {
-                return Err(anyhow::anyhow!(
-                    "Error initiating GAP descriptor discovery procedure; rc={}",
-                    rc
-                ));
+        loop {
+            let event =
+                super::procedure_recv(&rx, deadline, self.conn_handle, "descriptor discovery")?;
+            if Self::on_descriptor_discovery_event(&mut descriptors, event)? {
+                return Ok(descriptors);
             }
+        }
+    }
+
+    // Same as get_descriptors, without blocking. There's no deadline, drop
+    // the future (or disconnect) to give up.
+    pub async fn get_descriptors_async(&self) -> Result<Vec<BlePeerDescriptor>, BleError> {
+        let (tx, mut rx) = super::channel::channel();
+        self.start_descriptor_discovery(tx)?;
+
+        let mut descriptors = vec![];
+        loop {
+            let event = super::procedure_next(&mut rx, "descriptor discovery").await?;
+            if Self::on_descriptor_discovery_event(&mut descriptors, event)? {
+                return Ok(descriptors);
+            }
+        }
+    }
 
-            // Wait for results.
-            loop {
-                match rx.recv() {
-                    Ok(_) => break,
-                    Err(e) => anyhow::bail!("Error retrieving device descriptors: {}", e),
+    fn start_descriptor_discovery(
+        &self,
+        tx: impl BleEventSender<BlePeerDescriptorDiscoveryEvent> + 'static,
+    ) -> Result<(), BleError> {
+        log::info!("Retrieving descriptors for characteristic {}", self);
+
+        let cb_arg = super::procedure_cb_arg(tx);
+        let rc = unsafe {
+            esp_idf_sys::ble_gattc_disc_all_dscs(
+                self.conn_handle as u16,
+                self.val_handle,
+                self.end_handle,
+                Some(BlePeerCharacteristic::ble_on_gatt_disc_dscs),
+                cb_arg,
+            )
+        };
+        if let Err(e) = BleError::check(rc) {
+            unsafe { super::procedure_release::<BlePeerDescriptorDiscoveryEvent>(cb_arg) };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Returns true once the discovery is complete.
+    fn on_descriptor_discovery_event(
+        descriptors: &mut Vec<BlePeerDescriptor>,
+        event: BlePeerDescriptorDiscoveryEvent,
+    ) -> Result<bool, BleError> {
+        match event {
+            BlePeerDescriptorDiscoveryEvent::Discovery(dsc) => {
+                log::info!("Found: {}", dsc);
+                descriptors.push(dsc);
+                Ok(false)
+            }
+            BlePeerDescriptorDiscoveryEvent::DiscoveryFinished(status)
+                if status == esp_idf_sys::BLE_HS_EDONE as u16 =>
+            {
+                // Characteristics found by UUID don't know where they end,
+                // their range stops at the next characteristic declaration.
+                if let Some(end) = descriptors
+                    .iter()
+                    .position(|dsc| dsc.uuid == CHR_DECLARATION_UUID)
+                {
+                    descriptors.truncate(end);
                 }
+                Ok(true)
             }
+            BlePeerDescriptorDiscoveryEvent::DiscoveryFinished(status) => {
+                Err(BleError::from_code(status as u32)
+                    .unwrap_or(BleError::Aborted("descriptor discovery")))
+            }
+        }
+    }
+
+    // Enables notifications, or indications when the characteristic only
+    // supports those.
+    pub fn set_notify(&self, value: bool) -> Result<(), BleError> {
+        self.set_subscription(self.notify_mode(value))
+    }
+
+    pub async fn set_notify_async(&self, value: bool) -> Result<(), BleError> {
+        self.set_subscription_async(self.notify_mode(value)).await
+    }
+
+    pub(super) fn notify_mode(&self, value: bool) -> BleSubscription {
+        match value {
+            true if !self.can_notify() && self.can_indicate() => BleSubscription::Indicate,
+            true => BleSubscription::Notify,
+            false => BleSubscription::None,
         }
+    }
 
-        Ok(descriptors)
+    // Enables notifications (or indications) and returns a channel that
+    // receives only this characteristic's values. Values for characteristics
+    // with active subscribers are no longer queued in the device events
+    // channel. The channel is closed when the device disconnects.
+    pub fn subscribe(&self) -> Result<Receiver<Vec<u8>>, BleError> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let id = self.add_subscriber(Box::new(tx))?;
+        if let Err(e) = self.set_notify(true) {
+            self.remove_subscriber(id);
+            return Err(e);
+        }
+        Ok(rx)
     }
 
-    pub fn set_notify(&self, value: bool) -> Result<()> {
-        if !self.can_notify() {
-            anyhow::bail!("Characteristic doesn't support notifications");
+    // Same as subscribe, the values can be awaited.
+    pub async fn subscribe_async(&self) -> Result<BleReceiver<Vec<u8>>, BleError> {
+        let (tx, rx) = super::channel::channel();
+        let id = self.add_subscriber(Box::new(tx))?;
+        if let Err(e) = self.set_notify_async(true).await {
+            self.remove_subscriber(id);
+            return Err(e);
+        }
+        Ok(rx)
+    }
+
+    // Added before the CCCD is written, so no value is missed. Returns the id
+    // to remove it with if enabling the notifications fails.
+    pub(super) fn add_subscriber(
+        &self,
+        tx: Box<dyn BleEventSender<Vec<u8>>>,
+    ) -> Result<usize, BleError> {
+        let id = NEXT_SUBSCRIBER_ID.fetch_add(1, Ordering::Relaxed);
+        let ble = self.ble.upgrade().ok_or(BleError::StackGone)?;
+        let mut ble = ble.lock();
+        match ble
+            .devices
+            .values_mut()
+            .find(|shared| shared.conn_handle == Some(self.conn_handle))
+        {
+            Some(shared) => {
+                shared
+                    .subscribers
+                    .entry(self.val_handle)
+                    .or_default()
+                    .push((id, tx));
+                Ok(id)
+            }
+            None => Err(BleError::NotConnected),
         }
+    }
 
-        let uuid = BleUUID::parse("0229")?; // 0x2902 al reves.
-        let dsc = self.get_descriptor_by_uuid(&uuid)?;
+    pub(super) fn remove_subscriber(&self, id: usize) {
+        if let Some(ble) = self.ble.upgrade() {
+            let mut ble = ble.lock();
+            if let Some(subscribers) = ble
+                .devices
+                .values_mut()
+                .find(|shared| shared.conn_handle == Some(self.conn_handle))
+                .and_then(|shared| shared.subscribers.get_mut(&self.val_handle))
+            {
+                subscribers.retain(|(subscriber_id, _)| *subscriber_id != id);
+            }
+        }
+    }
 
-        match dsc {
-            Some(dsc) => {
-                log::info!("Found descriptor for set_notify: {}", dsc);
-                let data = match value {
-                    // 1 notifications (push sin ack)
-                    // 2 indications (push con ack)
-                    true => [1],
-                    // off.
-                    false => [0],
-                };
-                dsc.write(data)?;
+    pub fn set_subscription(&self, mode: BleSubscription) -> Result<(), BleError> {
+        self.check_subscription(mode)?;
+        let dsc = self.get_descriptor_by_uuid(&CCCD_UUID)?;
+        let dsc = self.cccd(mode, dsc)?;
+        dsc.write(&mode.cccd_value())
+    }
+
+    pub async fn set_subscription_async(&self, mode: BleSubscription) -> Result<(), BleError> {
+        self.check_subscription(mode)?;
+        let dsc = self.get_descriptor_by_uuid_async(&CCCD_UUID).await?;
+        let dsc = self.cccd(mode, dsc)?;
+        dsc.write_async(&mode.cccd_value()).await
+    }
+
+    // Same as set_subscription, with descriptors that were already
+    // discovered (see BlePeerDevice::discover_all).
+    pub(super) fn set_subscription_with(
+        &self,
+        mode: BleSubscription,
+        descriptors: &[BlePeerDescriptor],
+    ) -> Result<(), BleError> {
+        self.check_subscription(mode)?;
+        let dsc = descriptors
+            .iter()
+            .find(|dsc| dsc.uuid == CCCD_UUID)
+            .cloned();
+        let dsc = self.cccd(mode, dsc)?;
+        dsc.write(&mode.cccd_value())
+    }
+
+    pub(super) async fn set_subscription_with_async(
+        &self,
+        mode: BleSubscription,
+        descriptors: &[BlePeerDescriptor],
+    ) -> Result<(), BleError> {
+        self.check_subscription(mode)?;
+        let dsc = descriptors
+            .iter()
+            .find(|dsc| dsc.uuid == CCCD_UUID)
+            .cloned();
+        let dsc = self.cccd(mode, dsc)?;
+        dsc.write_async(&mode.cccd_value()).await
+    }
+
+    fn check_subscription(&self, mode: BleSubscription) -> Result<(), BleError> {
+        match mode {
+            BleSubscription::Notify | BleSubscription::Both if !self.can_notify() => {
+                Err(BleError::Unsupported("characteristic notifications"))
+            }
+            BleSubscription::Indicate | BleSubscription::Both if !self.can_indicate() => {
+                Err(BleError::Unsupported("characteristic indications"))
             }
-            None => anyhow::bail!(
-                "Invalid characteristic, supports notifications \
-                but descriptor to configure them wasn't found"
+            BleSubscription::None if !self.can_notify() && !self.can_indicate() => Err(
+                BleError::Unsupported("characteristic notifications nor indications"),
             ),
+            _ => Ok(()),
         }
+    }
 
-        Ok(())
+    fn cccd(
+        &self,
+        mode: BleSubscription,
+        dsc: Option<BlePeerDescriptor>,
+    ) -> Result<BlePeerDescriptor, BleError> {
+        match dsc {
+            Some(dsc) => {
+                log::info!("Found descriptor for set_subscription({:?}): {}", mode, dsc);
+                Ok(dsc)
+            }
+            None => Err(BleError::Invalid(format!(
+                "characteristic {} supports notifications \
+                but descriptor to configure them wasn't found",
+                self
+            ))),
+        }
     }
 
     unsafe extern "C" fn ble_on_gatt_disc_dscs(
@@ -182,22 +447,24 @@ impl BlePeerCharacteristic {
         dsc: *const esp_idf_sys::ble_gatt_dsc,
         cb_arg: *mut esp_idf_sys::c_types::c_void,
     ) -> esp_idf_sys::c_types::c_int {
-        let cb_arg = (cb_arg as *mut Box<dyn FnMut(BlePeerDescriptorDiscoveryEvent)>)
-            .as_mut()
-            .unwrap();
-        if !dsc.is_null() {
+        let status = if error.is_null() { 0 } else { (*error).status };
+        if status == 0 && !dsc.is_null() {
             let dsc = *dsc;
-            cb_arg(BlePeerDescriptorDiscoveryEvent::Discovery(
-                BlePeerDescriptor {
+            super::procedure_send(
+                cb_arg,
+                BlePeerDescriptorDiscoveryEvent::Discovery(BlePeerDescriptor {
                     conn_handle: conn_handle as BleConnHandle,
                     chr_val_handle,
                     handle: dsc.handle,
                     uuid: BleUUID::from(dsc.uuid),
-                },
-            ));
-        }
-        if (if error.is_null() { 0 } else { (*error).status }) == esp_idf_sys::BLE_HS_EDONE as u16 {
-            cb_arg(BlePeerDescriptorDiscoveryEvent::DiscoveryFinished);
+                }),
+            );
+        } else {
+            super::procedure_send(
+                cb_arg,
+                BlePeerDescriptorDiscoveryEvent::DiscoveryFinished(status),
+            );
+            super::procedure_release::<BlePeerDescriptorDiscoveryEvent>(cb_arg);
         }
         0
     }
@@ -217,86 +484,385 @@ impl std::fmt::Display for BlePeerCharacteristic {
     }
 }
 
-type BlePeerWriteResult = u16;
+// Opcode and attribute handle of write requests and notifications.
+const ATT_HEADER_LEN: usize = 3;
 
-pub fn write(conn_handle: BleConnHandle, attr_handle: u16, data: &[u8]) -> Result<()> {
-    let mtu = unsafe { esp_idf_sys::ble_att_mtu(conn_handle as u16) };
-    if data.len() > mtu.into() {
-        anyhow::bail!("BLE chr: data ({}) exceeds MTU size ({})", data.len(), mtu);
-    }
+// Longest attribute value allowed by the spec, whatever the MTU.
+const ATTR_MAX_LEN: usize = esp_idf_sys::BLE_ATT_ATTR_MAX_LEN as usize;
 
-    // Convert data into a raw pointer that we will later cast to c_void.
-    let data_len = data.len();
-    let data: *const _ = data;
+enum BlePeerReadEvent {
+    Data(Vec<u8>),
+    Finished(u16),
+}
+
+pub fn read(conn_handle: BleConnHandle, attr_handle: u16) -> Result<Vec<u8>, BleError> {
+    read_attr(conn_handle, attr_handle, false)
+}
+
+pub fn read_long(conn_handle: BleConnHandle, attr_handle: u16) -> Result<Vec<u8>, BleError> {
+    read_attr(conn_handle, attr_handle, true)
+}
 
-    // Create the callback that sends back the result through a channel.
+pub async fn read_async(conn_handle: BleConnHandle, attr_handle: u16) -> Result<Vec<u8>, BleError> {
+    read_attr_async(conn_handle, attr_handle, false).await
+}
+
+pub async fn read_long_async(
+    conn_handle: BleConnHandle,
+    attr_handle: u16,
+) -> Result<Vec<u8>, BleError> {
+    read_attr_async(conn_handle, attr_handle, true).await
+}
+
+fn read_attr(
+    conn_handle: BleConnHandle,
+    attr_handle: u16,
+    long: bool,
+) -> Result<Vec<u8>, BleError> {
+    let deadline = Instant::now() + Ble::timeouts().read;
+
+    // Read, results are sent back through a channel.
     let (tx, rx) = std::sync::mpsc::channel();
-    let mut callback: Box<dyn FnMut(BlePeerWriteResult)> =
-        Box::new(move |event| tx.send(event).unwrap());
+    start_read(conn_handle, attr_handle, long, tx)?;
 
-    // Write.
-    let cb_arg: *mut _ = &mut callback;
+    // Wait for results.
+    let mut value = vec![];
+    loop {
+        let event = super::procedure_recv(&rx, deadline, conn_handle, "read")?;
+        if on_read_event(&mut value, event, long)? {
+            return Ok(value);
+        }
+    }
+}
+
+async fn read_attr_async(
+    conn_handle: BleConnHandle,
+    attr_handle: u16,
+    long: bool,
+) -> Result<Vec<u8>, BleError> {
+    let (tx, mut rx) = super::channel::channel();
+    start_read(conn_handle, attr_handle, long, tx)?;
+
+    let mut value = vec![];
+    loop {
+        let event = super::procedure_next(&mut rx, "read").await?;
+        if on_read_event(&mut value, event, long)? {
+            return Ok(value);
+        }
+    }
+}
+
+fn start_read(
+    conn_handle: BleConnHandle,
+    attr_handle: u16,
+    long: bool,
+    tx: impl BleEventSender<BlePeerReadEvent> + 'static,
+) -> Result<(), BleError> {
+    let cb_arg = super::procedure_cb_arg(tx);
     let rc = unsafe {
-        esp_idf_sys::ble_gattc_write_flat(
-            conn_handle as u16,
-            attr_handle,
-            data as *const esp_idf_sys::c_types::c_void,
-            data_len as u16,
-            Some(ble_gattc_on_write),
-            cb_arg as *mut esp_idf_sys::c_types::c_void,
-        )
+        if long {
+            esp_idf_sys::ble_gattc_read_long(
+                conn_handle as u16,
+                attr_handle,
+                0,
+                Some(ble_gattc_on_read_long),
+                cb_arg,
+            )
+        } else {
+            esp_idf_sys::ble_gattc_read(
+                conn_handle as u16,
+                attr_handle,
+                Some(ble_gattc_on_read),
+                cb_arg,
+            )
+        }
     };
-    if rc != 0 {
-        anyhow::bail!(
-            "BLE write: error writing conn_handle={} attr_handle={} rc={}",
-            conn_handle,
-            attr_handle,
-            rc
+    if let Err(e) = BleError::check(rc) {
+        unsafe { super::procedure_release::<BlePeerReadEvent>(cb_arg) };
+        return Err(e);
+    }
+    Ok(())
+}
+
+// Returns true once the whole value was read. A plain read reports a single
+// chunk, a long read reports one chunk per ATT request and then BLE_HS_EDONE.
+fn on_read_event(
+    value: &mut Vec<u8>,
+    event: BlePeerReadEvent,
+    long: bool,
+) -> Result<bool, BleError> {
+    match event {
+        BlePeerReadEvent::Data(data) => {
+            value.extend_from_slice(&data);
+            Ok(!long)
+        }
+        BlePeerReadEvent::Finished(status)
+            if status == 0 || status == esp_idf_sys::BLE_HS_EDONE as u16 =>
+        {
+            Ok(true)
+        }
+        BlePeerReadEvent::Finished(status) => {
+            Err(BleError::from_code(status as u32).unwrap_or(BleError::Aborted("read")))
+        }
+    }
+}
+
+unsafe fn on_read(
+    error: *const esp_idf_sys::ble_gatt_error,
+    attr: *mut esp_idf_sys::ble_gatt_attr,
+    cb_arg: *mut esp_idf_sys::c_types::c_void,
+) -> u16 {
+    let status = if error.is_null() { 0 } else { (*error).status };
+    if status == 0 && !attr.is_null() {
+        super::procedure_send(
+            cb_arg,
+            BlePeerReadEvent::Data(super::os_mbuf_to_vec((*attr).om)),
         );
+    } else {
+        super::procedure_send(cb_arg, BlePeerReadEvent::Finished(status));
+    }
+    status
+}
+
+// A plain read calls back only once.
+unsafe extern "C" fn ble_gattc_on_read(
+    _conn_handle: u16,
+    error: *const esp_idf_sys::ble_gatt_error,
+    attr: *mut esp_idf_sys::ble_gatt_attr,
+    cb_arg: *mut esp_idf_sys::c_types::c_void,
+) -> esp_idf_sys::c_types::c_int {
+    on_read(error, attr, cb_arg);
+    super::procedure_release::<BlePeerReadEvent>(cb_arg);
+    0
+}
+
+unsafe extern "C" fn ble_gattc_on_read_long(
+    _conn_handle: u16,
+    error: *const esp_idf_sys::ble_gatt_error,
+    attr: *mut esp_idf_sys::ble_gatt_attr,
+    cb_arg: *mut esp_idf_sys::c_types::c_void,
+) -> esp_idf_sys::c_types::c_int {
+    if on_read(error, attr, cb_arg) != 0 {
+        super::procedure_release::<BlePeerReadEvent>(cb_arg);
     }
+    0
+}
+
+type BlePeerWriteResult = u16;
+
+pub fn write(conn_handle: BleConnHandle, attr_handle: u16, data: &[u8]) -> Result<(), BleError> {
+    let deadline = Instant::now() + Ble::timeouts().write;
+
+    // Write, the result is sent back through a channel.
+    let (tx, rx) = std::sync::mpsc::channel();
+    start_write(conn_handle, attr_handle, data, tx)?;
 
     // Wait for results.
-    let rc = loop {
-        match rx.recv() {
-            Ok(rc) => break rc,
-            Err(e) => anyhow::bail!("BLE write: error waiting for response {}", e),
+    write_result(super::procedure_recv(&rx, deadline, conn_handle, "write")?)
+}
+
+pub async fn write_async(
+    conn_handle: BleConnHandle,
+    attr_handle: u16,
+    data: &[u8],
+) -> Result<(), BleError> {
+    let (tx, mut rx) = super::channel::channel();
+    start_write(conn_handle, attr_handle, data, tx)?;
+    write_result(super::procedure_next(&mut rx, "write").await?)
+}
+
+fn start_write(
+    conn_handle: BleConnHandle,
+    attr_handle: u16,
+    data: &[u8],
+    tx: impl BleEventSender<BlePeerWriteResult> + 'static,
+) -> Result<(), BleError> {
+    if data.len() > ATTR_MAX_LEN {
+        log::error!(
+            "BLE chr: data ({}) exceeds the attribute size limit ({})",
+            data.len(),
+            ATTR_MAX_LEN
+        );
+        return Err(BleError::MessageSize);
+    }
+    // Values that don't fit in a single request go in a long write, sent in
+    // chunks with prepare write requests and applied at once by the peer.
+    let mtu = unsafe { esp_idf_sys::ble_att_mtu(conn_handle as u16) };
+    let long = data.len() > usize::from(mtu).saturating_sub(ATT_HEADER_LEN);
+
+    // Convert data into a raw pointer that we will later cast to c_void.
+    // NimBLE copies it into an mbuf before returning.
+    let data_len = data.len();
+    let data: *const _ = data;
+
+    let cb_arg = super::procedure_cb_arg(tx);
+    let rc = unsafe {
+        if long {
+            let om = esp_idf_sys::ble_hs_mbuf_from_flat(
+                data as *const esp_idf_sys::c_types::c_void,
+                data_len as u16,
+            );
+            if om.is_null() {
+                super::procedure_release::<BlePeerWriteResult>(cb_arg);
+                return Err(BleError::NoMemory);
+            }
+            // Takes the mbuf, even on failure.
+            esp_idf_sys::ble_gattc_write_long(
+                conn_handle as u16,
+                attr_handle,
+                0,
+                om,
+                Some(ble_gattc_on_write),
+                cb_arg,
+            )
+        } else {
+            esp_idf_sys::ble_gattc_write_flat(
+                conn_handle as u16,
+                attr_handle,
+                data as *const esp_idf_sys::c_types::c_void,
+                data_len as u16,
+                Some(ble_gattc_on_write),
+                cb_arg,
+            )
         }
     };
-    if rc != 0 as u16 {
-        anyhow::bail!(
-            "BLE write: unexpected response conn_handle={} attr_handle={} rc={}",
-            conn_handle,
-            attr_handle,
-            rc
+    if let Err(e) = BleError::check(rc) {
+        unsafe { super::procedure_release::<BlePeerWriteResult>(cb_arg) };
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn write_result(rc: BlePeerWriteResult) -> Result<(), BleError> {
+    match BleError::from_code(rc as u32) {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+unsafe extern "C" fn ble_gattc_on_write(
+    conn_handle: u16,
+    error: *const esp_idf_sys::ble_gatt_error,
+    _attr: *mut esp_idf_sys::ble_gatt_attr,
+    cb_arg: *mut esp_idf_sys::c_types::c_void,
+) -> esp_idf_sys::c_types::c_int {
+    let status = if error.is_null() { 0 } else { (*error).status };
+    log::info!(
+        "ble_gattc_on_write conn_handle={} status={}",
+        conn_handle,
+        status
+    );
+    super::procedure_send::<BlePeerWriteResult>(cb_arg, status);
+    super::procedure_release::<BlePeerWriteResult>(cb_arg);
+    0
+}
+
+// Writes several attributes at once: the peer queues every value and only
+// applies them if all of them could be queued. At most
+// CONFIG_BT_NIMBLE_GATT_WRITE_MAX_ATTRS (4 by default) attributes, as
+// (attribute handle, value).
+pub fn write_reliable(conn_handle: BleConnHandle, writes: &[(u16, &[u8])]) -> Result<(), BleError> {
+    let deadline = Instant::now() + Ble::timeouts().write;
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    start_write_reliable(conn_handle, writes, tx)?;
+
+    write_result(super::procedure_recv(
+        &rx,
+        deadline,
+        conn_handle,
+        "reliable write",
+    )?)
+}
+
+pub async fn write_reliable_async(
+    conn_handle: BleConnHandle,
+    writes: &[(u16, &[u8])],
+) -> Result<(), BleError> {
+    let (tx, mut rx) = super::channel::channel();
+    start_write_reliable(conn_handle, writes, tx)?;
+    write_result(super::procedure_next(&mut rx, "reliable write").await?)
+}
+
+fn start_write_reliable(
+    conn_handle: BleConnHandle,
+    writes: &[(u16, &[u8])],
+    tx: impl BleEventSender<BlePeerWriteResult> + 'static,
+) -> Result<(), BleError> {
+    if writes.iter().any(|(_, data)| data.len() > ATTR_MAX_LEN) {
+        log::error!(
+            "BLE chr: data exceeds the attribute size limit ({})",
+            ATTR_MAX_LEN
         );
+        return Err(BleError::MessageSize);
     }
 
+    let mut attrs: Vec<esp_idf_sys::ble_gatt_attr> = Vec::with_capacity(writes.len());
+    for (attr_handle, data) in writes {
+        let om = unsafe {
+            esp_idf_sys::ble_hs_mbuf_from_flat(
+                data.as_ptr() as *const esp_idf_sys::c_types::c_void,
+                data.len() as u16,
+            )
+        };
+        if om.is_null() {
+            for attr in &attrs {
+                unsafe { esp_idf_sys::os_mbuf_free_chain(attr.om) };
+            }
+            return Err(BleError::NoMemory);
+        }
+        attrs.push(esp_idf_sys::ble_gatt_attr {
+            handle: *attr_handle,
+            offset: 0,
+            om,
+        });
+    }
+
+    // NimBLE copies the attributes and takes their mbufs, even on failure.
+    let cb_arg = super::procedure_cb_arg(tx);
+    let rc = unsafe {
+        esp_idf_sys::ble_gattc_write_reliable(
+            conn_handle as u16,
+            attrs.as_mut_ptr(),
+            attrs.len() as esp_idf_sys::c_types::c_int,
+            Some(ble_gattc_on_write_reliable),
+            cb_arg,
+        )
+    };
+    if let Err(e) = BleError::check(rc) {
+        unsafe { super::procedure_release::<BlePeerWriteResult>(cb_arg) };
+        return Err(e);
+    }
     Ok(())
 }
 
-unsafe extern "C" fn ble_gattc_on_write(
+unsafe extern "C" fn ble_gattc_on_write_reliable(
     conn_handle: u16,
     error: *const esp_idf_sys::ble_gatt_error,
-    attr: *mut esp_idf_sys::ble_gatt_attr,
+    _attrs: *mut esp_idf_sys::ble_gatt_attr,
+    _num_attrs: u8,
     cb_arg: *mut esp_idf_sys::c_types::c_void,
 ) -> esp_idf_sys::c_types::c_int {
+    let status = if error.is_null() { 0 } else { (*error).status };
     log::info!(
-        "ble_gattc_on_write conn_handle={} error={:?} attr={:?}",
+        "ble_gattc_on_write_reliable conn_handle={} status={}",
         conn_handle,
-        (*error),
-        (*attr),
+        status
     );
-    let cb_arg = (cb_arg as *mut Box<dyn FnMut(BlePeerWriteResult)>)
-        .as_mut()
-        .unwrap();
-    cb_arg((*error).status);
+    super::procedure_send::<BlePeerWriteResult>(cb_arg, status);
+    super::procedure_release::<BlePeerWriteResult>(cb_arg);
     0
 }
 
-pub fn write_no_response(conn_handle: BleConnHandle, attr_handle: u16, data: &[u8]) -> Result<()> {
+pub fn write_no_response(
+    conn_handle: BleConnHandle,
+    attr_handle: u16,
+    data: &[u8],
+) -> Result<(), BleError> {
     let mtu = unsafe { esp_idf_sys::ble_att_mtu(conn_handle as u16) };
-    if data.len() > mtu.into() {
-        anyhow::bail!("BLE chr: data ({}) exceeds MTU size ({})", data.len(), mtu);
+    if data.len() > usize::from(mtu).saturating_sub(ATT_HEADER_LEN) {
+        log::error!("BLE chr: data ({}) exceeds MTU size ({})", data.len(), mtu);
+        return Err(BleError::MessageSize);
     }
 
     // Convert data into a raw pointer that we will later cast to c_void.
@@ -304,22 +870,12 @@ pub fn write_no_response(conn_handle: BleConnHandle, attr_handle: u16, data: &[u
     let data: *const _ = data;
 
     // Write.
-    let rc = unsafe {
+    BleError::check(unsafe {
         esp_idf_sys::ble_gattc_write_no_rsp_flat(
             conn_handle as u16,
             attr_handle,
             data as *const esp_idf_sys::c_types::c_void,
             data_len as u16,
         )
-    };
-    if rc != 0 {
-        anyhow::bail!(
-            "BLE write_no_response: error writing conn_handle={} attr_handle={} rc={}",
-            conn_handle,
-            attr_handle,
-            rc
-        );
-    }
-
-    Ok(())
+    })
 }
@@ -1,121 +1,615 @@
 use super::{
+    channel::BleEventSender,
     dev::{BleConnHandle, BlePeerDevice, BlePeerDeviceAddress},
-    SafeBle,
+    error::BleError,
+    gatt_store, Ble, BlePeerDeviceSharedState, SafeBle,
 };
-use anyhow::Result;
-use std::sync::Arc;
+use std::sync::{mpsc::RecvTimeoutError, Arc};
+use std::time::{Duration, Instant};
 
-pub(crate) enum BleConnectEvent {
+// Everything NimBLE reports about a connection made with BleClient.
+pub enum BleConnectionEvent {
+    // Link is up, MTU exchange and pairing still pending.
+    Established(BleConnHandle),
+    // Ready to use, sent once per connection.
     Connected(BleConnHandle),
-    Error(u16),
-    Disconnected(BleConnHandle),
-    Notification(Vec<u8>),
-    Indication(Vec<u8>),
+    Error(BleError),
+    // With the reason, usually BleError::Hci (e.g. 0x08, supervision timeout).
+    Disconnected(BleConnHandle, BleError),
+    // Attribute handle and value.
+    Notification(u16, Vec<u8>),
+    Indication(u16, Vec<u8>),
+    // Negotiated ATT MTU.
+    MtuChanged(BleConnHandle, u16),
+    // Pairing, or restoring the keys of a bond, finished.
+    EncryptionChanged(BleConnHandle, Result<(), BleError>),
+    // The peer asked for new connection parameters, either directly or
+    // through L2CAP. They are accepted, ConnParamsUpdated follows.
+    ConnParamsUpdateRequested(BleConnHandle, BleConnParams),
+    // Result of a parameter update, requested by either side. See
+    // BlePeerDevice::conn_info for the new values.
+    ConnParamsUpdated(BleConnHandle, Result<(), BleError>),
+    // The peer changed its subscription to one of our characteristics, see
+    // BleGattServer.
+    Subscribed {
+        conn_handle: BleConnHandle,
+        attr_handle: u16,
+        notify: bool,
+        indicate: bool,
+    },
+    // The peer's resolvable private address belongs to this identity.
+    IdentityResolved(BleConnHandle, BlePeerDeviceAddress),
+    // TX and RX PHY in use.
+    PhyUpdated(BleConnHandle, Result<(BlePhy, BlePhy), BleError>),
+    // GAP events without a variant of their own, by BLE_GAP_EVENT_* type.
+    Other(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlePhy {
+    Le1M,
+    Le2M,
+    LeCoded,
+    Unknown(u8),
+}
+
+impl From<u8> for BlePhy {
+    fn from(phy: u8) -> Self {
+        match phy as u32 {
+            esp_idf_sys::BLE_GAP_LE_PHY_1M => Self::Le1M,
+            esp_idf_sys::BLE_GAP_LE_PHY_2M => Self::Le2M,
+            esp_idf_sys::BLE_GAP_LE_PHY_CODED => Self::LeCoded,
+            _ => Self::Unknown(phy),
+        }
+    }
+}
+
+// Connection parameters, the controller picks an interval within the range.
+#[derive(Clone, Copy, Debug)]
+pub struct BleConnParams {
+    // Multiples of 1.25ms, from 7.5ms to 4s.
+    pub interval_min: Duration,
+    pub interval_max: Duration,
+    // Connection events the peripheral may skip.
+    pub latency: u16,
+    // Multiples of 10ms, from 100ms to 32s. Must be larger than
+    // (1 + latency) * interval_max * 2.
+    pub supervision_timeout: Duration,
+}
+
+impl BleConnParams {
+    // 1.25ms units.
+    fn interval_units(interval: Duration) -> u16 {
+        (interval.as_micros() / 1250) as u16
+    }
+
+    // 10ms units.
+    fn timeout_units(&self) -> u16 {
+        (self.supervision_timeout.as_millis() / 10) as u16
+    }
+
+    pub(super) fn native(&self) -> esp_idf_sys::ble_gap_conn_params {
+        esp_idf_sys::ble_gap_conn_params {
+            scan_itvl: 0x0010,
+            scan_window: 0x0010,
+            itvl_min: Self::interval_units(self.interval_min),
+            itvl_max: Self::interval_units(self.interval_max),
+            latency: self.latency,
+            supervision_timeout: self.timeout_units(),
+            min_ce_len: 0x0010,
+            max_ce_len: 0x0300,
+        }
+    }
+
+    pub(super) fn from_native_update(params: &esp_idf_sys::ble_gap_upd_params) -> Self {
+        Self {
+            interval_min: Duration::from_micros(params.itvl_min as u64 * 1250),
+            interval_max: Duration::from_micros(params.itvl_max as u64 * 1250),
+            latency: params.latency,
+            supervision_timeout: Duration::from_millis(params.supervision_timeout as u64 * 10),
+        }
+    }
+
+    pub(super) fn native_update(&self) -> esp_idf_sys::ble_gap_upd_params {
+        esp_idf_sys::ble_gap_upd_params {
+            itvl_min: Self::interval_units(self.interval_min),
+            itvl_max: Self::interval_units(self.interval_max),
+            latency: self.latency,
+            supervision_timeout: self.timeout_units(),
+            min_ce_len: 0x0010,
+            max_ce_len: 0x0300,
+        }
+    }
+}
+
+// Same values NimBLE uses when no parameters are given.
+impl Default for BleConnParams {
+    fn default() -> Self {
+        Self {
+            interval_min: Duration::from_millis(30),
+            interval_max: Duration::from_millis(50),
+            latency: 0,
+            supervision_timeout: Duration::from_millis(2560),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BleConnectOptions {
+    // How long the controller tries to establish the link. The whole connect
+    // call, pairing included, is bounded by BleTimeouts::connect.
+    pub establish_timeout: Duration,
+    pub params: BleConnParams,
+    // LE data length extension as (tx octets, tx time in us), None keeps the
+    // controller defaults.
+    pub data_length: Option<(u16, u16)>,
+    // ATT MTU we ask for, the peer might settle for less. It's a global
+    // NimBLE setting, so it applies to every connection.
+    pub preferred_mtu: u16,
+    // Pair (or restore the bond) after the MTU exchange, see
+    // Ble::set_security. Without it the connection is ready as soon as the
+    // MTU is negotiated, for peers that don't support pairing.
+    pub security: bool,
+    // Let the controller connect as soon as the device advertises, through
+    // its filter accept list (whitelist), instead of connecting right away.
+    // Needs no scan, only the address, and waits up to establish_timeout.
+    // The list is replaced by this device alone, so it's exclusive: don't mix
+    // it with anything else relying on the list. Direct connects need no scan
+    // either and leave the list alone.
+    pub filter_accept_list: bool,
+}
+
+impl Default for BleConnectOptions {
+    fn default() -> Self {
+        Self {
+            establish_timeout: Duration::from_secs(10),
+            params: Default::default(),
+            data_length: Some((251, 2120)),
+            preferred_mtu: 512,
+            security: true,
+            filter_accept_list: false,
+        }
+    }
 }
 
 pub struct BleClient {
     ble: SafeBle,
+    // Devices connected through this client, disconnected on drop.
+    connected: Vec<BlePeerDeviceAddress>,
 }
 
 impl BleClient {
     pub fn new(ble: SafeBle) -> Self {
-        let client = Self { ble };
+        let client = Self {
+            ble,
+            connected: vec![],
+        };
         client
     }
 
-    pub fn connect(&mut self, device: &BlePeerDevice) -> Result<()> {
+    pub fn connect(&mut self, device: &BlePeerDevice) -> Result<(), BleError> {
+        self.connect_with_options(device, &Default::default())
+    }
+
+    pub fn connect_with_options(
+        &mut self,
+        device: &BlePeerDevice,
+        options: &BleConnectOptions,
+    ) -> Result<(), BleError> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.start_connect(device, options, tx)?;
+
+        // Wait until it's finished.
+        let deadline = Instant::now() + Ble::timeouts().connect;
+        loop {
+            match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(event) => {
+                    if let Some(conn_handle) = self.on_connect_event(device, event)? {
+                        self.finish_connect(device, conn_handle, options, |shared| {
+                            shared.event_rx = Some(rx)
+                        })?;
+                        if !self.connected.contains(device.address()) {
+                            self.connected.push(device.address().clone());
+                        }
+                        return Ok(());
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    log::error!("BLE client: connection to {} timed out", device.address());
+                    self.cancel_connect(device);
+                    return Err(BleError::Timeout("connect"));
+                }
+                // The connection was replaced by another connect call.
+                Err(RecvTimeoutError::Disconnected) => {
+                    device
+                        .shared_state_mod(|shared| {
+                            shared.connecting = false;
+                        })
+                        .ok();
+                    return Err(BleError::Aborted("connect"));
+                }
+            }
+        }
+    }
+
+    pub async fn connect_async(&mut self, device: &BlePeerDevice) -> Result<(), BleError> {
+        self.connect_with_options_async(device, &Default::default())
+            .await
+    }
+
+    // Same as connect_with_options, without blocking. Events are then awaited
+    // with BlePeerDevice::next_event instead of use_events_channel. There's
+    // no deadline besides establish_timeout, dropping the future aborts the
+    // connection attempt.
+    pub async fn connect_with_options_async(
+        &mut self,
+        device: &BlePeerDevice,
+        options: &BleConnectOptions,
+    ) -> Result<(), BleError> {
+        let (tx, mut rx) = super::channel::channel();
+        self.start_connect(device, options, tx)?;
+
+        let mut guard = BleConnectGuard {
+            client: self,
+            device,
+            armed: true,
+        };
+        let conn_handle = loop {
+            let event = match rx.recv().await {
+                Some(event) => event,
+                // The connection was replaced by another connect call.
+                None => {
+                    guard.armed = false;
+                    device
+                        .shared_state_mod(|shared| {
+                            shared.connecting = false;
+                        })
+                        .ok();
+                    return Err(BleError::Aborted("connect"));
+                }
+            };
+            match guard.client.on_connect_event(device, event) {
+                Ok(Some(conn_handle)) => break conn_handle,
+                Ok(None) => continue,
+                Err(e) => {
+                    guard.armed = false;
+                    return Err(e);
+                }
+            }
+        };
+        guard.armed = false;
+        drop(guard);
+
+        self.finish_connect(device, conn_handle, options, |shared| {
+            shared.async_event_rx = Some(rx)
+        })?;
+        if !self.connected.contains(device.address()) {
+            self.connected.push(device.address().clone());
+        }
+        Ok(())
+    }
+
+    // Starts connecting, the connection events are sent to tx.
+    fn start_connect(
+        &self,
+        device: &BlePeerDevice,
+        options: &BleConnectOptions,
+        tx: impl BleEventSender<BleConnectionEvent> + Send + 'static,
+    ) -> Result<(), BleError> {
         log::info!("Connecting to device {}", device);
+        let own_addr_type = self.ble.lock().own_addr_type()?;
+        BleError::check(unsafe { esp_idf_sys::ble_att_set_preferred_mtu(options.preferred_mtu) })?;
+        let conn_params = options.params.native();
+        let data_length = options.data_length;
+        let security = options.security;
+        let filter_accept_list = options.filter_accept_list;
+        // Bonded peers are known by identity address, the ID address types let
+        // the controller resolve whatever private address they currently use.
+        let mut direct_addr = device.address().0;
+        let bonded = self.ble.lock().bonded_peers()?.contains(device.address());
+        if bonded && (direct_addr.type_ as u32) < esp_idf_sys::BLE_ADDR_PUBLIC_ID {
+            direct_addr.type_ += esp_idf_sys::BLE_ADDR_PUBLIC_ID as u8;
+        }
+        if filter_accept_list {
+            BleError::check(unsafe { esp_idf_sys::ble_gap_wl_set(&device.address().0, 1) })?;
+        }
 
-        let rx = device.shared_state_mod(|shared| {
+        device.shared_state_mod(|shared| {
             // Callback.
             let ble = Arc::downgrade(&self.ble);
-            let (tx, rx) = std::sync::mpsc::channel();
-            shared.callback = Some(Box::new(move |event| {
-                match event {
-                    BleConnectEvent::Disconnected(conn_handle) => match ble.upgrade() {
-                        Some(ble) => {
-                            let mut ble = ble.lock();
-                            for (_addr, shared) in &mut ble.devices {
-                                if let Some(dev_conn_handle) = shared.conn_handle {
-                                    if dev_conn_handle == conn_handle {
-                                        shared.conn_handle = None;
-                                        break;
+            let address = device.address().clone();
+            // Connected is only reported once, later MTU or encryption changes
+            // are just forwarded.
+            let mut ready = false;
+            let id = super::conn::register(
+                &address,
+                Box::new(move |event| {
+                    match &event {
+                        // Negotiate a bigger data length and ATT MTU before
+                        // pairing.
+                        BleConnectionEvent::Established(conn_handle) => {
+                            if let Some((tx_octets, tx_time)) = data_length {
+                                let rc = unsafe {
+                                    esp_idf_sys::ble_hs_hci_util_set_data_len(
+                                        *conn_handle as u16,
+                                        tx_octets,
+                                        tx_time,
+                                    )
+                                };
+                                if let Err(e) = BleError::check(rc) {
+                                    log::error!("Set packet length failed; rc = {}", rc);
+                                    tx.send_event(BleConnectionEvent::Error(e));
+                                }
+                            }
+                            let rc = unsafe {
+                                esp_idf_sys::ble_gattc_exchange_mtu(
+                                    *conn_handle as u16,
+                                    None,
+                                    std::ptr::null_mut(),
+                                )
+                            };
+                            if let Err(e) = BleError::check(rc) {
+                                log::error!("MTU exchange error: rc={}", rc);
+                                tx.send_event(BleConnectionEvent::Error(e));
+                            }
+                        }
+                        BleConnectionEvent::MtuChanged(conn_handle, _) if security && !ready => {
+                            let rc = unsafe {
+                                esp_idf_sys::ble_gap_security_initiate(*conn_handle as u16)
+                            };
+                            if let Err(e) = BleError::check(rc) {
+                                log::error!(
+                                    "Error initiating ble_gap_security_initiate: rc={}",
+                                    rc
+                                );
+                                tx.send_event(BleConnectionEvent::Error(e));
+                            }
+                        }
+                        BleConnectionEvent::MtuChanged(conn_handle, _) if !ready => {
+                            ready = true;
+                            tx.send_event(BleConnectionEvent::Connected(*conn_handle));
+                        }
+                        BleConnectionEvent::EncryptionChanged(conn_handle, Ok(()))
+                            if security && !ready =>
+                        {
+                            ready = true;
+                            tx.send_event(BleConnectionEvent::Connected(*conn_handle));
+                        }
+                        BleConnectionEvent::Disconnected(conn_handle, _) => match ble.upgrade() {
+                            Some(ble) => {
+                                let mut ble = ble.lock();
+                                for (_addr, shared) in &mut ble.devices {
+                                    if let Some(dev_conn_handle) = shared.conn_handle {
+                                        if dev_conn_handle == *conn_handle {
+                                            shared.conn_handle = None;
+                                            shared.subscribers.clear();
+                                            shared.gatt = None;
+                                            shared.service_changed_handle = None;
+                                            break;
+                                        }
                                     }
                                 }
                             }
+                            None => {
+                                log::warn!("BLE client: disconnect after the stack was dropped")
+                            }
+                        },
+                        BleConnectionEvent::IdentityResolved(_, identity) => {
+                            if let Some(ble) = ble.upgrade() {
+                                let mut ble = ble.lock();
+                                if let Some(shared) = ble.devices.get_mut(&address) {
+                                    shared.identity_address = Some(identity.clone());
+                                }
+                            }
                         }
-                        None => panic!("Couldn't upgrade BLE weak reference"),
-                    },
-                    // We only care about disconnects here, the rest of the
-                    // events are only queued in the event channel to be handled
-                    // by the user.
-                    _ => {}
-                };
-                tx.send(event).unwrap();
-            }));
+                        // Values of subscribed characteristics go straight to
+                        // their subscribers.
+                        BleConnectionEvent::Notification(attr_handle, data)
+                        | BleConnectionEvent::Indication(attr_handle, data) => {
+                            if let Some(ble) = ble.upgrade() {
+                                let mut ble = ble.lock();
+                                // The attribute tree is stale, it's discovered
+                                // again on the next discover_all.
+                                let services_changed = match ble.devices.get_mut(&address) {
+                                    Some(shared)
+                                        if shared.service_changed_handle == Some(*attr_handle) =>
+                                    {
+                                        shared.gatt = None;
+                                        Some(
+                                            shared
+                                                .identity_address
+                                                .clone()
+                                                .unwrap_or_else(|| address.clone()),
+                                        )
+                                    }
+                                    _ => None,
+                                };
+                                if let Some(identity) = services_changed {
+                                    log::info!("BLE client: services of {} changed", address);
+                                    if let Some(nvs) = ble.gatt_store() {
+                                        if let Err(e) = gatt_store::remove(nvs, &identity) {
+                                            log::warn!("BLE client: {}", e);
+                                        }
+                                    }
+                                }
+                                if let Some(subscribers) = ble
+                                    .devices
+                                    .get_mut(&address)
+                                    .and_then(|shared| shared.subscribers.get_mut(attr_handle))
+                                {
+                                    subscribers.retain(|(_, tx)| tx.send_event(data.clone()));
+                                    if !subscribers.is_empty() {
+                                        return;
+                                    }
+                                }
+                            }
+                        }
+                        // The rest of the events are only queued in the event
+                        // channel to be handled by the user.
+                        _ => {}
+                    };
+                    // Nobody is listening if connect timed out.
+                    tx.send_event(event);
+                }),
+            )?;
+            shared.connecting = true;
 
-            // Start the connection thread.
-            let cb_arg: *mut _ = &mut shared.callback;
-            unsafe {
+            // With the filter accept list there's no peer address, the
+            // controller connects to whichever device in the list shows up.
+            let peer_addr = match filter_accept_list {
+                true => std::ptr::null(),
+                false => &direct_addr as *const _,
+            };
+
+            // Start the connection thread. The registry id is all NimBLE gets.
+            let rc = unsafe {
                 esp_idf_sys::ble_gap_connect(
-                    esp_idf_sys::BLE_OWN_ADDR_PUBLIC as u8,
-                    &device.address().0,
-                    10000,
-                    std::ptr::null(),
+                    own_addr_type,
+                    peer_addr,
+                    options.establish_timeout.as_millis() as i32,
+                    &conn_params,
                     Some(Self::ble_on_gap_connect_event),
-                    cb_arg as *mut esp_idf_sys::c_types::c_void,
+                    id as *mut esp_idf_sys::c_types::c_void,
                 )
             };
+            if rc != 0 {
+                super::conn::unregister(id);
+                shared.connecting = false;
+            }
+            BleError::check(rc)
+        })?
+    }
 
-            // Return event channel.
-            rx
-        });
-
-        // Wait until it's finished.
-        loop {
-            match rx.recv() {
-                Ok(BleConnectEvent::Connected(conn_handle)) => {
-                    device.shared_state_mod(|shared| {
-                        shared.event_rx = Some(rx);
-                        shared.conn_handle = Some(conn_handle);
-                    });
-                    break Ok(());
-                }
-                Ok(BleConnectEvent::Disconnected(_)) => {
-                    device.shared_state_mod(|shared| {
+    // Handles an event received while connecting, returns the connection
+    // handle once it's ready.
+    fn on_connect_event(
+        &self,
+        device: &BlePeerDevice,
+        event: BleConnectionEvent,
+    ) -> Result<Option<BleConnHandle>, BleError> {
+        match event {
+            BleConnectionEvent::Connected(conn_handle) => return Ok(Some(conn_handle)),
+            // The peer dropped the link while pairing, for instance.
+            BleConnectionEvent::Disconnected(_, reason) => {
+                log::error!(
+                    "BLE client: {} disconnected while connecting: {}",
+                    device,
+                    reason
+                );
+                device
+                    .shared_state_mod(|shared| {
                         shared.conn_handle = None;
-                    });
-                }
-                Ok(BleConnectEvent::Error(rc)) => {
-                    device.shared_state_mod(|shared| {
+                        shared.connecting = false;
+                    })
+                    .ok();
+                return Err(reason);
+            }
+            BleConnectionEvent::Error(e) | BleConnectionEvent::EncryptionChanged(_, Err(e)) => {
+                // The link might be up already.
+                self.cancel_connect(device);
+                device
+                    .shared_state_mod(|shared| {
                         shared.conn_handle = None;
-                    });
-                    anyhow::bail!("Connection failed! rc={}", rc);
-                }
-                Ok(_) => panic!("Unexpected event received"),
-                Err(e) => panic!("Error receving connection event: {}", e),
+                    })
+                    .ok();
+                return Err(e);
             }
+            BleConnectionEvent::Established(_)
+            | BleConnectionEvent::MtuChanged(..)
+            | BleConnectionEvent::EncryptionChanged(..)
+            | BleConnectionEvent::ConnParamsUpdateRequested(..)
+            | BleConnectionEvent::ConnParamsUpdated(..)
+            | BleConnectionEvent::Subscribed { .. }
+            | BleConnectionEvent::IdentityResolved(..)
+            | BleConnectionEvent::PhyUpdated(..)
+            | BleConnectionEvent::Other(_) => {}
+            // Can't happen before the link is up, but they are harmless.
+            _ => log::warn!("BLE client: unexpected event while connecting"),
         }
+        Ok(None)
     }
 
-    fn disconnect(&self, address: &BlePeerDeviceAddress) -> Result<()> {
+    // Marks the device as connected, keeping the event channel with store.
+    fn finish_connect(
+        &self,
+        device: &BlePeerDevice,
+        conn_handle: BleConnHandle,
+        options: &BleConnectOptions,
+        store: impl FnOnce(&mut BlePeerDeviceSharedState),
+    ) -> Result<(), BleError> {
+        device.shared_state_mod(|shared| {
+            store(shared);
+            shared.conn_handle = Some(conn_handle);
+            shared.connecting = false;
+        })?;
+        let info = device.conn_info()?;
+        log::info!("Connected to device {}: {:?}", device, info);
+        // Bonded peers using resolvable private addresses are resolved by the
+        // controller, IdentityResolved only comes after pairing.
+        let mut desc: esp_idf_sys::ble_gap_conn_desc = Default::default();
+        if unsafe { esp_idf_sys::ble_gap_conn_find(conn_handle as u16, &mut desc) } == 0 {
+            let identity = BlePeerDeviceAddress(desc.peer_id_addr);
+            if &identity != device.address() {
+                device.shared_state_mod(|shared| shared.identity_address = Some(identity))?;
+            }
+        }
+        if options.security && self.ble.lock().security().mitm && !info.authenticated {
+            log::error!("BLE client: {} didn't authenticate", device);
+            self.disconnect(device.address()).ok();
+            return Err(BleError::Authentication);
+        }
+        Ok(())
+    }
+
+    // Aborts a connection attempt, either while the link is being established
+    // or during MTU exchange / pairing.
+    fn cancel_connect(&self, device: &BlePeerDevice) {
+        unsafe {
+            esp_idf_sys::ble_gap_conn_cancel();
+            let mut desc: esp_idf_sys::ble_gap_conn_desc = Default::default();
+            if esp_idf_sys::ble_gap_conn_find_by_addr(&device.address().0, &mut desc) == 0 {
+                esp_idf_sys::ble_gap_terminate(
+                    desc.conn_handle,
+                    esp_idf_sys::BLE_ERR_REM_USER_CONN_TERM as u8,
+                );
+            }
+        }
+        device
+            .shared_state_mod(|shared| {
+                shared.connecting = false;
+            })
+            .ok();
+    }
+
+    // Waits for the disconnection unless the events channel isn't available:
+    // connections made with connect_async, or while use_events_channel runs.
+    pub fn disconnect(&self, address: &BlePeerDeviceAddress) -> Result<(), BleError> {
         log::info!("BLE client: disconnecting from {} ...", address);
         let (conn_handle, event_rx) = {
             let mut ble = self.ble.lock();
-            let shared = ble.devices.get_mut(address).unwrap();
-            let event_rx = std::mem::take(&mut shared.event_rx).unwrap();
-            (shared.conn_handle.unwrap(), event_rx)
+            let shared = ble
+                .devices
+                .get_mut(address)
+                .ok_or(BleError::UnknownDevice)?;
+            match shared.conn_handle {
+                Some(conn_handle) => (conn_handle, std::mem::take(&mut shared.event_rx)),
+                None => return Err(BleError::NotConnected),
+            }
         };
-        if unsafe { esp_idf_sys::ble_gap_terminate(conn_handle as u16, 19) } == 0 {
-            loop {
-                match event_rx.recv() {
-                    Ok(BleConnectEvent::Disconnected(_)) => break,
-                    Ok(_) => continue,
-                    Err(_) => panic!("Unexpected error waiting for disconnected"),
-                }
+        BleError::check(unsafe { esp_idf_sys::ble_gap_terminate(conn_handle as u16, 19) })?;
+        let event_rx = match event_rx {
+            Some(event_rx) => event_rx,
+            None => return Ok(()),
+        };
+        let deadline = Instant::now() + Ble::timeouts().disconnect;
+        loop {
+            match event_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(BleConnectionEvent::Disconnected(..)) => return Ok(()),
+                Ok(_) => continue,
+                Err(RecvTimeoutError::Timeout) => return Err(BleError::Timeout("disconnect")),
+                Err(RecvTimeoutError::Disconnected) => return Err(BleError::Aborted("disconnect")),
             }
         }
-        Ok(())
     }
 
     unsafe extern "C" fn ble_on_gap_connect_event(
@@ -123,91 +617,206 @@ impl BleClient {
         cb_arg: *mut esp_idf_sys::c_types::c_void,
     ) -> esp_idf_sys::c_types::c_int {
         let event = *event;
-        let cb_arg = (cb_arg as *mut Box<dyn FnMut(BleConnectEvent)>)
-            .as_mut()
-            .unwrap();
+        // cb_arg is the connection id in the registry, see conn::register.
+        let id = cb_arg as usize;
 
         match event.type_ as u32 {
             esp_idf_sys::BLE_GAP_EVENT_CONNECT => {
                 log::info!("BLE gap event, BLE_GAP_EVENT_CONNECT");
+                let conn_handle = event.__bindgen_anon_1.connect.conn_handle as BleConnHandle;
                 if event.__bindgen_anon_1.connect.status == 0 {
-                    let rc = esp_idf_sys::ble_hs_hci_util_set_data_len(
-                        event.__bindgen_anon_1.connect.conn_handle,
-                        251,
-                        2120,
+                    super::conn::dispatch(
+                        id,
+                        conn_handle,
+                        BleConnectionEvent::Established(conn_handle),
                     );
-                    if rc != 0 {
-                        log::error!("Set packet length failed; rc = {}", rc);
-                        cb_arg(BleConnectEvent::Error(rc.try_into().unwrap()));
-                    }
-                    let rc = esp_idf_sys::ble_att_set_preferred_mtu(512);
-                    if rc != 0 {
-                        log::error!("Failed to set preferred MTU; rc = {}", rc);
-                        cb_arg(BleConnectEvent::Error(rc.try_into().unwrap()));
-                    }
-                    let rc = esp_idf_sys::ble_gattc_exchange_mtu(
-                        event.__bindgen_anon_1.connect.conn_handle,
-                        None,
-                        std::mem::MaybeUninit::zeroed().assume_init(),
-                    );
-                    if rc != 0 {
-                        log::error!("MTU exchange error: rc={}", rc);
-                        cb_arg(BleConnectEvent::Error(rc.try_into().unwrap()));
-                    }
                 } else {
                     log::error!(
                         "unexpected connection status: {}",
                         event.__bindgen_anon_1.connect.status
                     );
-                    cb_arg(BleConnectEvent::Error(
-                        event.__bindgen_anon_1.connect.status.try_into().unwrap(),
-                    ));
+                    super::conn::dispatch(
+                        id,
+                        conn_handle,
+                        BleConnectionEvent::Error(
+                            BleError::from_code(event.__bindgen_anon_1.connect.status as u32)
+                                .unwrap_or(BleError::UnknownError),
+                        ),
+                    );
+                    super::conn::unregister(id);
                 }
 
                 0
             }
 
             esp_idf_sys::BLE_GAP_EVENT_DISCONNECT => {
-                log::info!("BLE gap event, BLE_GAP_EVENT_DISCONNECT");
-                cb_arg(BleConnectEvent::Disconnected(
-                    event.__bindgen_anon_1.disconnect.conn.conn_handle.into(),
-                ));
+                let conn_handle = event.__bindgen_anon_1.disconnect.conn.conn_handle.into();
+                let reason = BleError::from_code(event.__bindgen_anon_1.disconnect.reason as u32)
+                    .unwrap_or(BleError::UnknownError);
+                log::info!("BLE gap event, BLE_GAP_EVENT_DISCONNECT: {}", reason);
+                super::conn::dispatch(
+                    id,
+                    conn_handle,
+                    BleConnectionEvent::Disconnected(conn_handle, reason),
+                );
                 0
             }
 
             esp_idf_sys::BLE_GAP_EVENT_ENC_CHANGE => {
                 log::info!("BLE gap event, BLE_GAP_EVENT_ENC_CHANGE");
-                cb_arg(BleConnectEvent::Connected(
-                    event.__bindgen_anon_1.enc_change.conn_handle as BleConnHandle,
-                ));
+                let conn_handle = event.__bindgen_anon_1.enc_change.conn_handle as BleConnHandle;
+                let status = BleError::check(event.__bindgen_anon_1.enc_change.status);
+                if let Err(e) = &status {
+                    log::error!("BLE client: encryption failed: {}", e);
+                }
+                super::conn::dispatch(
+                    id,
+                    conn_handle,
+                    BleConnectionEvent::EncryptionChanged(conn_handle, status),
+                );
                 0
             }
 
             esp_idf_sys::BLE_GAP_EVENT_NOTIFY_RX => {
-                let data_p = (*event.__bindgen_anon_1.notify_rx.om).om_data;
-                let data_len = (*event.__bindgen_anon_1.notify_rx.om).om_len;
-                let data = std::slice::from_raw_parts(data_p, data_len as usize);
-
-                if event.__bindgen_anon_1.notify_rx.indication() == 1 {
-                    cb_arg(BleConnectEvent::Indication(data.to_vec()));
+                // Indications are confirmed by NimBLE before calling us, so
+                // both are handled the same way from here on.
+                let conn_handle = event.__bindgen_anon_1.notify_rx.conn_handle as BleConnHandle;
+                let attr_handle = event.__bindgen_anon_1.notify_rx.attr_handle;
+                let data = super::os_mbuf_to_vec(event.__bindgen_anon_1.notify_rx.om);
+                let event = if event.__bindgen_anon_1.notify_rx.indication() == 1 {
+                    BleConnectionEvent::Indication(attr_handle, data)
                 } else {
-                    cb_arg(BleConnectEvent::Notification(data.to_vec()));
-                }
+                    BleConnectionEvent::Notification(attr_handle, data)
+                };
+                super::conn::dispatch(id, conn_handle, event);
                 0
             }
 
             esp_idf_sys::BLE_GAP_EVENT_MTU => {
                 log::info!("BLE gap event, BLE_GAP_EVENT_MTU");
-                let rc =
-                    esp_idf_sys::ble_gap_security_initiate(event.__bindgen_anon_1.mtu.conn_handle);
-                if rc != 0 {
-                    log::error!("Error initiating ble_gap_security_initiate: rc={}", rc);
-                    cb_arg(BleConnectEvent::Error(rc.try_into().unwrap()));
+                let conn_handle = event.__bindgen_anon_1.mtu.conn_handle as BleConnHandle;
+                super::conn::dispatch(
+                    id,
+                    conn_handle,
+                    BleConnectionEvent::MtuChanged(conn_handle, event.__bindgen_anon_1.mtu.value),
+                );
+                0
+            }
+
+            esp_idf_sys::BLE_GAP_EVENT_PASSKEY_ACTION => {
+                log::info!("BLE gap event, BLE_GAP_EVENT_PASSKEY_ACTION");
+                super::security::on_passkey_action(
+                    event.__bindgen_anon_1.passkey.conn_handle,
+                    &event.__bindgen_anon_1.passkey.params,
+                );
+                0
+            }
+
+            esp_idf_sys::BLE_GAP_EVENT_REPEAT_PAIRING => {
+                log::info!("BLE gap event, BLE_GAP_EVENT_REPEAT_PAIRING");
+                super::security::on_repeat_pairing(
+                    event.__bindgen_anon_1.repeat_pairing.conn_handle,
+                )
+            }
+
+            esp_idf_sys::BLE_GAP_EVENT_CONN_UPDATE => {
+                log::info!("BLE gap event, BLE_GAP_EVENT_CONN_UPDATE");
+                let conn_handle = event.__bindgen_anon_1.conn_update.conn_handle as BleConnHandle;
+                super::conn::dispatch(
+                    id,
+                    conn_handle,
+                    BleConnectionEvent::ConnParamsUpdated(
+                        conn_handle,
+                        BleError::check(event.__bindgen_anon_1.conn_update.status),
+                    ),
+                );
+                0
+            }
+
+            // We accept the peer's parameters as they are, which is what
+            // NimBLE does without a callback.
+            esp_idf_sys::BLE_GAP_EVENT_CONN_UPDATE_REQ
+            | esp_idf_sys::BLE_GAP_EVENT_L2CAP_UPDATE_REQ => {
+                log::info!("BLE gap event, BLE_GAP_EVENT_CONN_UPDATE_REQ");
+                let conn_handle =
+                    event.__bindgen_anon_1.conn_update_req.conn_handle as BleConnHandle;
+                if let Some(params) = event.__bindgen_anon_1.conn_update_req.peer_params.as_ref() {
+                    super::conn::dispatch(
+                        id,
+                        conn_handle,
+                        BleConnectionEvent::ConnParamsUpdateRequested(
+                            conn_handle,
+                            BleConnParams::from_native_update(params),
+                        ),
+                    );
+                }
+                0
+            }
+
+            esp_idf_sys::BLE_GAP_EVENT_SUBSCRIBE => {
+                log::info!("BLE gap event, BLE_GAP_EVENT_SUBSCRIBE");
+                let subscribe = event.__bindgen_anon_1.subscribe;
+                let conn_handle = subscribe.conn_handle as BleConnHandle;
+                super::conn::dispatch(
+                    id,
+                    conn_handle,
+                    BleConnectionEvent::Subscribed {
+                        conn_handle,
+                        attr_handle: subscribe.attr_handle,
+                        notify: subscribe.cur_notify() == 1,
+                        indicate: subscribe.cur_indicate() == 1,
+                    },
+                );
+                0
+            }
+
+            // A peer using a resolvable private address was paired with,
+            // its IRK is stored along with the bond from now on.
+            esp_idf_sys::BLE_GAP_EVENT_IDENTITY_RESOLVED => {
+                let conn_handle = event.__bindgen_anon_1.identity_resolved.conn_handle;
+                let mut desc: esp_idf_sys::ble_gap_conn_desc = Default::default();
+                if esp_idf_sys::ble_gap_conn_find(conn_handle, &mut desc) == 0 {
+                    log::info!(
+                        "BLE gap event, BLE_GAP_EVENT_IDENTITY_RESOLVED {} is {}",
+                        BlePeerDeviceAddress(desc.peer_ota_addr),
+                        BlePeerDeviceAddress(desc.peer_id_addr),
+                    );
+                    super::conn::dispatch(
+                        id,
+                        conn_handle as BleConnHandle,
+                        BleConnectionEvent::IdentityResolved(
+                            conn_handle as BleConnHandle,
+                            BlePeerDeviceAddress(desc.peer_id_addr),
+                        ),
+                    );
                 }
                 0
             }
 
-            _ => 0,
+            esp_idf_sys::BLE_GAP_EVENT_PHY_UPDATE_COMPLETE => {
+                log::info!("BLE gap event, BLE_GAP_EVENT_PHY_UPDATE_COMPLETE");
+                let phy = event.__bindgen_anon_1.phy_updated;
+                let conn_handle = phy.conn_handle as BleConnHandle;
+                super::conn::dispatch(
+                    id,
+                    conn_handle,
+                    BleConnectionEvent::PhyUpdated(
+                        conn_handle,
+                        BleError::check(phy.status).map(|_| (phy.tx_phy.into(), phy.rx_phy.into())),
+                    ),
+                );
+                0
+            }
+
+            event_type => {
+                log::info!("BLE gap event, type {}", event_type);
+                super::conn::dispatch(
+                    id,
+                    esp_idf_sys::BLE_HS_CONN_HANDLE_NONE as BleConnHandle,
+                    BleConnectionEvent::Other(event_type as u8),
+                );
+                0
+            }
         }
     }
 }
@@ -218,19 +827,30 @@ impl Drop for BleClient {
 
         unsafe { esp_idf_sys::ble_gap_conn_cancel() };
 
-        let connected: Vec<BlePeerDeviceAddress> = {
-            let ble = self.ble.lock();
-            (&ble.devices)
-                .into_iter()
-                .filter(|(_, state)| state.conn_handle.is_some())
-                .map(|(addr, _)| addr.clone())
-                .collect()
-        };
-
-        for addr in connected {
+        // Devices connected by other clients stay connected.
+        for addr in std::mem::take(&mut self.connected) {
             self.disconnect(&addr).ok();
         }
 
         log::info!("BLE client: drop finished ...");
     }
 }
+
+// Aborts an async connection attempt whose future was dropped.
+struct BleConnectGuard<'a> {
+    client: &'a BleClient,
+    device: &'a BlePeerDevice,
+    armed: bool,
+}
+
+impl Drop for BleConnectGuard<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            log::warn!(
+                "BLE client: connection to {} aborted",
+                self.device.address()
+            );
+            self.client.cancel_connect(self.device);
+        }
+    }
+}
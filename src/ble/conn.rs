@@ -0,0 +1,108 @@
+// Registry of the connections made in the central role, used to route GAP
+// events to the right connection. NimBLE gets a plain id as cb_arg, never a
+// pointer, and events are looked up by conn_handle once the link is up, so
+// any number of peers can be connected at the same time (up to
+// CONFIG_BT_NIMBLE_MAX_CONNECTIONS).
+
+use super::client::BleConnectionEvent;
+use super::dev::{BleConnHandle, BlePeerDeviceAddress};
+use super::error::BleError;
+use esp_idf_hal::mutex::Mutex;
+use std::sync::Arc;
+
+static CONNECTIONS: Mutex<BleConnections> = Mutex::new(BleConnections {
+    next_id: 1,
+    entries: Vec::new(),
+});
+
+// Called from the NimBLE host task, never with the registry locked.
+pub(super) type BleConnectHandler = Box<dyn FnMut(BleConnectionEvent) + Send>;
+
+struct BleConnection {
+    id: usize,
+    address: BlePeerDeviceAddress,
+    conn_handle: Option<BleConnHandle>,
+    handler: Arc<Mutex<BleConnectHandler>>,
+}
+
+struct BleConnections {
+    next_id: usize,
+    entries: Vec<BleConnection>,
+}
+
+// Registers the handler of a new connection attempt and returns the id to pass
+// as cb_arg to ble_gap_connect. Fails with BleError::Busy while the address is
+// still connecting or connected, its entry goes away with the connection.
+pub(super) fn register(
+    address: &BlePeerDeviceAddress,
+    handler: BleConnectHandler,
+) -> Result<usize, BleError> {
+    let mut connections = CONNECTIONS.lock();
+    if connections
+        .entries
+        .iter()
+        .any(|entry| &entry.address == address)
+    {
+        log::warn!("BLE conn: {} is already connecting or connected", address);
+        return Err(BleError::Busy);
+    }
+    let id = connections.next_id;
+    // 0 would be a null cb_arg.
+    connections.next_id = connections.next_id.checked_add(1).unwrap_or(1);
+    connections.entries.push(BleConnection {
+        id,
+        address: address.clone(),
+        conn_handle: None,
+        handler: Arc::new(Mutex::new(handler)),
+    });
+    Ok(id)
+}
+
+// Drops a connection attempt that failed before the link came up.
+pub(super) fn unregister(id: usize) {
+    CONNECTIONS.lock().entries.retain(|entry| entry.id != id);
+}
+
+// Delivers a GAP event of the connection registered as id. The entry is
+// removed once the connection is over, which closes its event channels.
+pub(super) fn dispatch(id: usize, conn_handle: BleConnHandle, event: BleConnectionEvent) {
+    let ends = matches!(event, BleConnectionEvent::Disconnected(..));
+    let handler = {
+        let mut connections = CONNECTIONS.lock();
+        let index = connections
+            .entries
+            .iter()
+            .position(|entry| entry.conn_handle == Some(conn_handle))
+            // Before the link is up, and for events without a conn_handle.
+            .or_else(|| connections.entries.iter().position(|entry| entry.id == id));
+        match index {
+            Some(index) => {
+                let entry = &mut connections.entries[index];
+                if let BleConnectionEvent::Established(conn_handle) = event {
+                    entry.conn_handle = Some(conn_handle);
+                }
+                entry.handler.clone()
+            }
+            None => {
+                log::warn!(
+                    "BLE conn: event for unknown connection id={} conn_handle={}",
+                    id,
+                    conn_handle
+                );
+                return;
+            }
+        }
+    };
+
+    {
+        let mut handler = handler.lock();
+        (*handler)(event);
+    }
+
+    if ends {
+        CONNECTIONS
+            .lock()
+            .entries
+            .retain(|entry| !Arc::ptr_eq(&entry.handler, &handler));
+    }
+}
@@ -1,17 +1,41 @@
-use super::client::BleConnectEvent;
+use super::channel::{BleEventSender, BleReceiver};
+use super::chr::{BlePeerCharacteristic, BleSubscription};
+use super::client::{BleConnParams, BleConnectionEvent};
+use super::error::BleError;
+use super::gatt::{BlePeerGatt, BlePeerGattCharacteristic, BlePeerGattService};
+use super::gatt_std::{self, BATTERY_LEVEL_CHR_UUID, BATTERY_SVC_UUID};
+use super::gatt_store::{self, BleStoredGatt};
+use super::scan::BleAdvertisementReport;
 use super::svc::BlePeerService;
 use super::uuid::BleUUID;
 use super::{Ble, BlePeerDeviceSharedState};
-use anyhow::Result;
 use esp_idf_hal::mutex::Mutex;
+use esp_idf_svc::nvs::EspDefaultNvs;
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::mpsc::Receiver;
-use std::sync::Weak;
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 pub type BleConnHandle = u32;
 
+// Parameters currently in use by a connection.
+#[derive(Clone, Copy, Debug)]
+pub struct BleConnInfo {
+    pub interval: Duration,
+    pub latency: u16,
+    pub supervision_timeout: Duration,
+    pub mtu: u16,
+    pub encrypted: bool,
+    // Paired with MITM protection.
+    pub authenticated: bool,
+    pub bonded: bool,
+}
+
 enum BlePeerServiceDiscoveryEvent {
-    Discovery(BlePeerService),
-    DiscoveryFinished,
+    Discovery(u16, esp_idf_sys::ble_gatt_svc),
+    DiscoveryFinished(u16),
 }
 
 pub struct BlePeerDeviceAddress(pub esp_idf_sys::ble_addr_t);
@@ -31,6 +55,29 @@ impl std::cmp::PartialEq for BlePeerDeviceAddress {
 
 impl std::cmp::Eq for BlePeerDeviceAddress {}
 
+impl BlePeerDeviceAddress {
+    pub fn is_random(&self) -> bool {
+        matches!(
+            self.0.type_ as u32,
+            esp_idf_sys::BLE_ADDR_RANDOM | esp_idf_sys::BLE_ADDR_RANDOM_ID
+        )
+    }
+
+    // Changes every few minutes, bonded peers are reported with their
+    // identity address instead once the controller resolves them.
+    pub fn is_resolvable_private(&self) -> bool {
+        self.0.type_ as u32 == esp_idf_sys::BLE_ADDR_RANDOM && self.0.val[5] & 0xc0 == 0x40
+    }
+
+    // Address resolved by the controller using the IRK of a bonded peer.
+    pub fn is_identity(&self) -> bool {
+        matches!(
+            self.0.type_ as u32,
+            esp_idf_sys::BLE_ADDR_PUBLIC_ID | esp_idf_sys::BLE_ADDR_RANDOM_ID
+        )
+    }
+}
+
 impl std::fmt::Display for BlePeerDeviceAddress {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -62,103 +109,567 @@ impl BlePeerDevice {
         Self { address, ble }
     }
 
+    // The device might have been evicted from the registry, or the stack
+    // dropped, while this handle was kept around.
     pub(super) fn shared_state_get<T>(
         &self,
         getter: impl FnOnce(&BlePeerDeviceSharedState) -> T,
-    ) -> T {
-        let arc = self.ble.upgrade().unwrap();
+    ) -> Result<T, BleError> {
+        let arc = self.ble.upgrade().ok_or(BleError::StackGone)?;
         let ble = arc.lock();
-        let shared = ble.devices.get(&self.address).unwrap();
-        getter(shared)
+        let shared = ble
+            .devices
+            .get(&self.address)
+            .ok_or(BleError::UnknownDevice)?;
+        Ok(getter(shared))
     }
 
     pub(super) fn shared_state_mod<T>(
         &self,
         setter: impl FnOnce(&mut BlePeerDeviceSharedState) -> T,
-    ) -> T {
-        let arc = self.ble.upgrade().unwrap();
+    ) -> Result<T, BleError> {
+        let arc = self.ble.upgrade().ok_or(BleError::StackGone)?;
         let mut ble = arc.lock();
-        let shared = ble.devices.get_mut(&self.address).unwrap();
-        setter(shared)
+        let shared = ble
+            .devices
+            .get_mut(&self.address)
+            .ok_or(BleError::UnknownDevice)?;
+        Ok(setter(shared))
+    }
+
+    // The address bonds are stored under: the same as address() unless the
+    // peer uses resolvable private addresses and its identity was resolved.
+    pub fn identity_address(&self) -> BlePeerDeviceAddress {
+        self.shared_state_get(|shared| shared.identity_address.clone())
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| self.address.clone())
     }
 
     pub fn conn_handle(&self) -> Option<BleConnHandle> {
         self.shared_state_get(|shared| shared.conn_handle.clone())
+            .unwrap_or(None)
     }
 
+    // Empty if the device is no longer known.
     pub fn name(&self) -> String {
         self.shared_state_get(|shared| shared.name.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn advertisement(&self) -> Result<BleAdvertisementReport, BleError> {
+        self.shared_state_get(|shared| shared.advertisement.clone())
+    }
+
+    pub fn rssi(&self) -> Result<i8, BleError> {
+        self.shared_state_get(|shared| shared.advertisement.rssi)
     }
 
     pub fn address(&self) -> &BlePeerDeviceAddress {
         &self.address
     }
 
-    pub(crate) fn use_events_channel(&self, handler: impl FnOnce(&Receiver<BleConnectEvent>)) {
-        let event_rx =
-            self.shared_state_mod(|shared| std::mem::take(&mut shared.event_rx).unwrap());
+    pub fn use_events_channel(
+        &self,
+        handler: impl FnOnce(&Receiver<BleConnectionEvent>),
+    ) -> Result<(), BleError> {
+        let event_rx = self
+            .shared_state_mod(|shared| std::mem::take(&mut shared.event_rx))?
+            .ok_or(BleError::NotConnected)?;
         handler(&event_rx);
         self.shared_state_mod(|shared| {
             if shared.conn_handle.is_some() {
                 shared.event_rx = Some(event_rx);
             }
-        });
+        })
+    }
+
+    // Next event of a connection made with BleClient::connect_async. After
+    // BleConnectionEvent::Disconnected it fails with BleError::NotConnected.
+    pub fn next_event(&self) -> BleNextEvent<'_> {
+        BleNextEvent(self)
     }
 
     pub fn is_connected(&self) -> bool {
         self.conn_handle().is_some()
     }
 
-    pub fn get_service_by_uuid(&mut self, uuid: &BleUUID) -> Result<Option<BlePeerService>> {
-        let services = self.get_services()?;
-        let svc = match services.into_iter().find(|svc| svc.uuid() == uuid) {
-            Some(svc) => svc,
-            None => return Ok(None),
+    pub fn conn_info(&self) -> Result<BleConnInfo, BleError> {
+        let conn_handle = self.conn_handle().ok_or(BleError::NotConnected)?;
+        let mut desc: esp_idf_sys::ble_gap_conn_desc = Default::default();
+        BleError::check(unsafe { esp_idf_sys::ble_gap_conn_find(conn_handle as u16, &mut desc) })?;
+        Ok(BleConnInfo {
+            // 1.25ms units.
+            interval: Duration::from_micros(desc.conn_itvl as u64 * 1250),
+            latency: desc.conn_latency,
+            // 10ms units.
+            supervision_timeout: Duration::from_millis(desc.supervision_timeout as u64 * 10),
+            mtu: unsafe { esp_idf_sys::ble_att_mtu(conn_handle as u16) },
+            encrypted: desc.sec_state.encrypted() == 1,
+            authenticated: desc.sec_state.authenticated() == 1,
+            bonded: desc.sec_state.bonded() == 1,
+        })
+    }
+
+    // Asks the peer for new connection parameters. The outcome is reported
+    // as BleConnectionEvent::ConnParamsUpdated in the events channel.
+    pub fn update_conn_params(&self, params: &BleConnParams) -> Result<(), BleError> {
+        let conn_handle = self.conn_handle().ok_or(BleError::NotConnected)?;
+        let params = params.native_update();
+        BleError::check(unsafe { esp_idf_sys::ble_gap_update_params(conn_handle as u16, &params) })
+    }
+
+    // Writes the characteristics all at once or none of them, see
+    // chr::write_reliable.
+    pub fn write_reliable(
+        &self,
+        writes: &[(&BlePeerCharacteristic, &[u8])],
+    ) -> Result<(), BleError> {
+        let (conn_handle, writes) = self.reliable_writes(writes)?;
+        super::chr::write_reliable(conn_handle, &writes)
+    }
+
+    pub async fn write_reliable_async(
+        &self,
+        writes: &[(&BlePeerCharacteristic, &[u8])],
+    ) -> Result<(), BleError> {
+        let (conn_handle, writes) = self.reliable_writes(writes)?;
+        super::chr::write_reliable_async(conn_handle, &writes).await
+    }
+
+    fn reliable_writes<'a>(
+        &self,
+        writes: &[(&BlePeerCharacteristic, &'a [u8])],
+    ) -> Result<(BleConnHandle, Vec<(u16, &'a [u8])>), BleError> {
+        let conn_handle = self.conn_handle().ok_or(BleError::NotConnected)?;
+        writes
+            .iter()
+            .map(|(chr, data)| match chr.can_write() {
+                true => Ok((chr.val_handle, *data)),
+                false => Err(BleError::Unsupported("characteristic writes")),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(|writes| (conn_handle, writes))
+    }
+
+    // From the cached attribute tree if there is one, see discover_all.
+    pub fn get_service_by_uuid(
+        &mut self,
+        uuid: &BleUUID,
+    ) -> Result<Option<BlePeerService>, BleError> {
+        match self.cached_gatt()? {
+            Some(gatt) => Ok(gatt.service(uuid).map(|svc| svc.service.clone())),
+            None => self.discover_service_by_uuid(uuid),
+        }
+    }
+
+    pub async fn get_service_by_uuid_async(
+        &mut self,
+        uuid: &BleUUID,
+    ) -> Result<Option<BlePeerService>, BleError> {
+        match self.cached_gatt()? {
+            Some(gatt) => Ok(gatt.service(uuid).map(|svc| svc.service.clone())),
+            None => self.discover_service_by_uuid_async(uuid).await,
+        }
+    }
+
+    pub fn get_services(&mut self) -> Result<Vec<BlePeerService>, BleError> {
+        self.discover_services(None)
+    }
+
+    pub async fn get_services_async(&mut self) -> Result<Vec<BlePeerService>, BleError> {
+        self.discover_services_async(None).await
+    }
+
+    // Discovers only the service with this UUID, always asking the peer.
+    pub fn discover_service_by_uuid(
+        &self,
+        uuid: &BleUUID,
+    ) -> Result<Option<BlePeerService>, BleError> {
+        Ok(self.discover_services(Some(uuid))?.into_iter().next())
+    }
+
+    pub async fn discover_service_by_uuid_async(
+        &self,
+        uuid: &BleUUID,
+    ) -> Result<Option<BlePeerService>, BleError> {
+        Ok(self
+            .discover_services_async(Some(uuid))
+            .await?
+            .into_iter()
+            .next())
+    }
+
+    // Services, characteristics and descriptors of the peer. Discovered once
+    // and kept until the device disconnects or indicates that its services
+    // changed. With Ble::set_gatt_store, the tree of bonded peers is stored
+    // and reused on later connections as long as the peer's Database Hash,
+    // or its services if it has none, didn't change.
+    pub fn discover_all(&mut self) -> Result<BlePeerGatt, BleError> {
+        if let Some(gatt) = self.cached_gatt()? {
+            return Ok(gatt);
+        }
+        let stored = match self.stored_gatt() {
+            Some(stored) => {
+                let current = match (&stored.database_hash, stored.gatt.database_hash()) {
+                    (Some(hash), Some(chr)) => {
+                        chr.characteristic.read().map(|value| value == *hash)
+                    }
+                    _ => self
+                        .get_services()
+                        .map(|services| stored.gatt.has_services(&services)),
+                };
+                self.check_stored_gatt(stored, current)
+            }
+            None => None,
         };
-        Ok(Some(svc))
+        let gatt = match stored {
+            Some(gatt) => gatt,
+            None => {
+                let gatt = self.discover_gatt()?;
+                let database_hash = match gatt.database_hash() {
+                    Some(chr) => chr.characteristic.read().ok(),
+                    None => None,
+                };
+                self.store_gatt(&gatt, database_hash);
+                gatt
+            }
+        };
+
+        let service_changed = gatt.service_changed();
+        if let Some(chr) = service_changed {
+            if let Err(e) = chr.set_subscription(BleSubscription::Indicate) {
+                log::warn!("Subscribing to service changes of {} failed: {}", self, e);
+            }
+        }
+        self.cache_gatt(&gatt, service_changed)?;
+        Ok(gatt)
     }
 
-    pub fn get_services(&mut self) -> Result<Vec<BlePeerService>> {
-        log::info!("Retrieving services for device {}", self);
+    pub async fn discover_all_async(&mut self) -> Result<BlePeerGatt, BleError> {
+        if let Some(gatt) = self.cached_gatt()? {
+            return Ok(gatt);
+        }
+        let stored = match self.stored_gatt() {
+            Some(stored) => {
+                let current = match (&stored.database_hash, stored.gatt.database_hash()) {
+                    (Some(hash), Some(chr)) => chr
+                        .characteristic
+                        .read_async()
+                        .await
+                        .map(|value| value == *hash),
+                    _ => self
+                        .get_services_async()
+                        .await
+                        .map(|services| stored.gatt.has_services(&services)),
+                };
+                self.check_stored_gatt(stored, current)
+            }
+            None => None,
+        };
+        let gatt = match stored {
+            Some(gatt) => gatt,
+            None => {
+                let gatt = self.discover_gatt_async().await?;
+                let database_hash = match gatt.database_hash() {
+                    Some(chr) => chr.characteristic.read_async().await.ok(),
+                    None => None,
+                };
+                self.store_gatt(&gatt, database_hash);
+                gatt
+            }
+        };
+
+        let service_changed = gatt.service_changed();
+        if let Some(chr) = service_changed {
+            if let Err(e) = chr.set_subscription_async(BleSubscription::Indicate).await {
+                log::warn!("Subscribing to service changes of {} failed: {}", self, e);
+            }
+        }
+        self.cache_gatt(&gatt, service_changed)?;
+        Ok(gatt)
+    }
+
+    // Forgets the attribute tree, the stored one too, so the next
+    // discover_all asks the peer again. For peers without a Service Changed
+    // characteristic.
+    pub fn invalidate_gatt_cache(&self) -> Result<(), BleError> {
+        self.shared_state_mod(|shared| shared.gatt = None)?;
+        match self.ble.upgrade().and_then(|ble| ble.lock().gatt_store()) {
+            Some(nvs) => gatt_store::remove(nvs, &self.identity_address()),
+            None => Ok(()),
+        }
+    }
+
+    // Charge left in percent, from the Battery Service.
+    pub fn battery_level(&mut self) -> Result<u8, BleError> {
+        let chr = self.battery_level_chr()?;
+        gatt_std::decode_battery_level(&chr.read()?)
+    }
+
+    pub async fn battery_level_async(&mut self) -> Result<u8, BleError> {
+        let chr = self.battery_level_chr_async().await?;
+        gatt_std::decode_battery_level(&chr.read_async().await?)
+    }
+
+    // Levels sent by the peer when they change, until it disconnects. The
+    // notifications are optional, peers that only support reading the level
+    // fail with BleError::Unsupported, poll battery_level instead.
+    pub fn subscribe_battery_level(&mut self) -> Result<Receiver<u8>, BleError> {
+        let chr = self.battery_level_chr()?;
+        check_battery_level_notify(&chr)?;
+        let (tx, rx) = std::sync::mpsc::channel();
+        let id = chr.add_subscriber(Box::new(BleBatteryLevelSender(tx)))?;
+        if let Err(e) = chr.set_notify(true) {
+            chr.remove_subscriber(id);
+            return Err(e);
+        }
+        Ok(rx)
+    }
+
+    pub async fn subscribe_battery_level_async(&mut self) -> Result<BleReceiver<u8>, BleError> {
+        let chr = self.battery_level_chr_async().await?;
+        check_battery_level_notify(&chr)?;
+        let (tx, rx) = super::channel::channel();
+        let id = chr.add_subscriber(Box::new(BleBatteryLevelSender(tx)))?;
+        if let Err(e) = chr.set_notify_async(true).await {
+            chr.remove_subscriber(id);
+            return Err(e);
+        }
+        Ok(rx)
+    }
 
-        if !self.is_connected() {
-            anyhow::bail!("Device not connected");
+    fn battery_level_chr(&mut self) -> Result<BlePeerCharacteristic, BleError> {
+        let svc = self
+            .get_service_by_uuid(&BATTERY_SVC_UUID)?
+            .ok_or(BleError::NotFound)?;
+        svc.get_characteristics_by_uuid(&BATTERY_LEVEL_CHR_UUID)?
+            .into_iter()
+            .next()
+            .ok_or(BleError::NotFound)
+    }
+
+    async fn battery_level_chr_async(&mut self) -> Result<BlePeerCharacteristic, BleError> {
+        let svc = self
+            .get_service_by_uuid_async(&BATTERY_SVC_UUID)
+            .await?
+            .ok_or(BleError::NotFound)?;
+        svc.get_characteristics_by_uuid_async(&BATTERY_LEVEL_CHR_UUID)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or(BleError::NotFound)
+    }
+
+    fn discover_gatt(&mut self) -> Result<BlePeerGatt, BleError> {
+        let mut services = vec![];
+        for service in self.get_services()? {
+            let mut characteristics = vec![];
+            for characteristic in service.get_characteristics()? {
+                let descriptors = match characteristic.val_handle < characteristic.end_handle {
+                    true => characteristic.get_descriptors()?,
+                    false => vec![],
+                };
+                characteristics.push(BlePeerGattCharacteristic {
+                    characteristic,
+                    descriptors,
+                });
+            }
+            services.push(BlePeerGattService {
+                service,
+                characteristics,
+            });
         }
+        Ok(BlePeerGatt { services })
+    }
 
+    async fn discover_gatt_async(&mut self) -> Result<BlePeerGatt, BleError> {
         let mut services = vec![];
-        {
-            // Callback.
-            let (tx, rx) = std::sync::mpsc::channel();
-            let services = &mut services;
-            let mut callback: Box<dyn FnMut(BlePeerServiceDiscoveryEvent)> =
-                Box::new(move |event| match event {
-                    BlePeerServiceDiscoveryEvent::Discovery(svc) => {
-                        log::info!("Found: {}", svc);
-                        services.push(svc);
-                    }
-                    BlePeerServiceDiscoveryEvent::DiscoveryFinished => tx.send(()).unwrap(),
+        for service in self.get_services_async().await? {
+            let mut characteristics = vec![];
+            for characteristic in service.get_characteristics_async().await? {
+                let descriptors = match characteristic.val_handle < characteristic.end_handle {
+                    true => characteristic.get_descriptors_async().await?,
+                    false => vec![],
+                };
+                characteristics.push(BlePeerGattCharacteristic {
+                    characteristic,
+                    descriptors,
                 });
+            }
+            services.push(BlePeerGattService {
+                service,
+                characteristics,
+            });
+        }
+        Ok(BlePeerGatt { services })
+    }
 
-            // Start the discovery thread.
-            let cb_arg: *mut _ = &mut callback;
-            unsafe {
-                esp_idf_sys::ble_gattc_disc_all_svcs(
-                    self.conn_handle().unwrap() as u16,
-                    Some(BlePeerDevice::ble_on_gatt_disc_svc),
-                    cb_arg as *mut esp_idf_sys::c_types::c_void,
-                )
-            };
+    fn cached_gatt(&self) -> Result<Option<BlePeerGatt>, BleError> {
+        self.shared_state_get(|shared| shared.gatt.clone())
+    }
 
-            // Wait for results.
-            loop {
-                match rx.recv() {
-                    Ok(_) => break,
-                    Err(e) => anyhow::bail!("Error retrieving device services: {}", e),
-                }
+    fn cache_gatt(
+        &self,
+        gatt: &BlePeerGatt,
+        service_changed: Option<&BlePeerGattCharacteristic>,
+    ) -> Result<(), BleError> {
+        let conn_handle = self.conn_handle();
+        self.shared_state_mod(|shared| {
+            // Not if the device disconnected during the discovery.
+            if conn_handle.is_some() {
+                shared.gatt = Some(gatt.clone());
+                shared.service_changed_handle =
+                    service_changed.map(|chr| chr.characteristic.val_handle);
             }
+        })
+    }
+
+    // Only for bonded peers, anyone could be using the address of the rest.
+    fn gatt_store(&self) -> Option<Arc<EspDefaultNvs>> {
+        if !self.conn_info().map(|info| info.bonded).unwrap_or(false) {
+            return None;
         }
+        self.ble.upgrade()?.lock().gatt_store()
+    }
 
-        Ok(services)
+    fn stored_gatt(&self) -> Option<BleStoredGatt> {
+        let nvs = self.gatt_store()?;
+        let conn_handle = self.conn_handle()?;
+        match gatt_store::load(nvs, &self.identity_address(), conn_handle, self.ble.clone()) {
+            Ok(stored) => stored,
+            Err(e) => {
+                log::warn!("Loading the GATT database of {} failed: {}", self, e);
+                None
+            }
+        }
+    }
+
+    // The stored tree if it still matches the peer (current is Ok(true)),
+    // otherwise it's dropped and the peer is discovered again.
+    fn check_stored_gatt(
+        &self,
+        stored: BleStoredGatt,
+        current: Result<bool, BleError>,
+    ) -> Option<BlePeerGatt> {
+        match current {
+            Ok(true) => {
+                log::info!("Using the stored GATT database of {}", self);
+                return Some(stored.gatt);
+            }
+            Ok(false) => log::info!("The GATT database of {} changed", self),
+            Err(e) => log::warn!("Checking the GATT database of {} failed: {}", self, e),
+        }
+        if let Some(nvs) = self.gatt_store() {
+            gatt_store::remove(nvs, &self.identity_address()).ok();
+        }
+        None
+    }
+
+    fn store_gatt(&self, gatt: &BlePeerGatt, database_hash: Option<Vec<u8>>) {
+        if let Some(nvs) = self.gatt_store() {
+            let address = self.identity_address();
+            if let Err(e) = gatt_store::save(nvs, &address, gatt, database_hash.as_deref()) {
+                log::warn!("Storing the GATT database of {} failed: {}", self, e);
+            }
+        }
+    }
+
+    fn discover_services(&self, uuid: Option<&BleUUID>) -> Result<Vec<BlePeerService>, BleError> {
+        let conn_handle = self.conn_handle().ok_or(BleError::NotConnected)?;
+        let deadline = Instant::now() + Ble::timeouts().discovery;
+
+        // Start the discovery, results are sent back through a channel.
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.start_service_discovery(conn_handle, uuid, tx)?;
+
+        // Wait for results.
+        let mut services = vec![];
+        loop {
+            let event = super::procedure_recv(&rx, deadline, conn_handle, "service discovery")?;
+            if self.on_service_discovery_event(&mut services, event)? {
+                return Ok(services);
+            }
+        }
+    }
+
+    async fn discover_services_async(
+        &self,
+        uuid: Option<&BleUUID>,
+    ) -> Result<Vec<BlePeerService>, BleError> {
+        let conn_handle = self.conn_handle().ok_or(BleError::NotConnected)?;
+        let (tx, mut rx) = super::channel::channel();
+        self.start_service_discovery(conn_handle, uuid, tx)?;
+
+        let mut services = vec![];
+        loop {
+            let event = super::procedure_next(&mut rx, "service discovery").await?;
+            if self.on_service_discovery_event(&mut services, event)? {
+                return Ok(services);
+            }
+        }
+    }
+
+    fn start_service_discovery(
+        &self,
+        conn_handle: BleConnHandle,
+        uuid: Option<&BleUUID>,
+        tx: impl BleEventSender<BlePeerServiceDiscoveryEvent> + 'static,
+    ) -> Result<(), BleError> {
+        log::info!("Retrieving services for device {}", self);
+
+        let cb_arg = super::procedure_cb_arg(tx);
+        let rc = unsafe {
+            match uuid {
+                Some(uuid) => esp_idf_sys::ble_gattc_disc_svc_by_uuid(
+                    conn_handle as u16,
+                    uuid.native() as *const _ as *const esp_idf_sys::ble_uuid_t,
+                    Some(BlePeerDevice::ble_on_gatt_disc_svc),
+                    cb_arg,
+                ),
+                None => esp_idf_sys::ble_gattc_disc_all_svcs(
+                    conn_handle as u16,
+                    Some(BlePeerDevice::ble_on_gatt_disc_svc),
+                    cb_arg,
+                ),
+            }
+        };
+        if let Err(e) = BleError::check(rc) {
+            unsafe { super::procedure_release::<BlePeerServiceDiscoveryEvent>(cb_arg) };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Returns true once the discovery is complete.
+    fn on_service_discovery_event(
+        &self,
+        services: &mut Vec<BlePeerService>,
+        event: BlePeerServiceDiscoveryEvent,
+    ) -> Result<bool, BleError> {
+        match event {
+            BlePeerServiceDiscoveryEvent::Discovery(conn_handle, svc) => {
+                let svc = BlePeerService {
+                    conn_handle,
+                    start_handle: svc.start_handle,
+                    end_handle: svc.end_handle,
+                    uuid: BleUUID::from(svc.uuid),
+                    ble: self.ble.clone(),
+                };
+                log::info!("Found: {}", svc);
+                services.push(svc);
+                Ok(false)
+            }
+            BlePeerServiceDiscoveryEvent::DiscoveryFinished(status)
+                if status == esp_idf_sys::BLE_HS_EDONE as u16 =>
+            {
+                Ok(true)
+            }
+            BlePeerServiceDiscoveryEvent::DiscoveryFinished(status) => {
+                Err(BleError::from_code(status as u32)
+                    .unwrap_or(BleError::Aborted("service discovery")))
+            }
+        }
     }
 
     unsafe extern "C" fn ble_on_gatt_disc_svc(
@@ -167,26 +678,74 @@ impl BlePeerDevice {
         svc: *const esp_idf_sys::ble_gatt_svc,
         cb_arg: *mut esp_idf_sys::c_types::c_void,
     ) -> esp_idf_sys::c_types::c_int {
-        let cb_arg = (cb_arg as *mut Box<dyn FnMut(BlePeerServiceDiscoveryEvent)>)
-            .as_mut()
-            .unwrap();
-
-        if !svc.is_null() {
-            let svc = *svc;
-            cb_arg(BlePeerServiceDiscoveryEvent::Discovery(BlePeerService {
-                conn_handle,
-                start_handle: svc.start_handle,
-                end_handle: svc.end_handle,
-                uuid: BleUUID::from(svc.uuid),
-            }));
-        }
-        if (if error.is_null() { 0 } else { (*error).status }) == esp_idf_sys::BLE_HS_EDONE as u16 {
-            cb_arg(BlePeerServiceDiscoveryEvent::DiscoveryFinished);
+        let status = if error.is_null() { 0 } else { (*error).status };
+        if status == 0 && !svc.is_null() {
+            super::procedure_send(
+                cb_arg,
+                BlePeerServiceDiscoveryEvent::Discovery(conn_handle, *svc),
+            );
+        } else {
+            super::procedure_send(
+                cb_arg,
+                BlePeerServiceDiscoveryEvent::DiscoveryFinished(status),
+            );
+            super::procedure_release::<BlePeerServiceDiscoveryEvent>(cb_arg);
         }
         0
     }
 }
 
+pub struct BleNextEvent<'a>(&'a BlePeerDevice);
+
+impl Future for BleNextEvent<'_> {
+    type Output = Result<BleConnectionEvent, BleError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let polled = self.0.shared_state_mod(|shared| {
+            let polled = match shared.async_event_rx.as_mut() {
+                Some(rx) => rx.poll_recv(cx),
+                None => Poll::Ready(None),
+            };
+            // The sender lives until the next connect, so the channel is
+            // dropped here to end the events of this connection.
+            if let Poll::Ready(None) | Poll::Ready(Some(BleConnectionEvent::Disconnected(..))) =
+                polled
+            {
+                shared.async_event_rx = None;
+            }
+            polled
+        });
+        match polled {
+            Ok(Poll::Ready(Some(event))) => Poll::Ready(Ok(event)),
+            Ok(Poll::Ready(None)) => Poll::Ready(Err(BleError::NotConnected)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+fn check_battery_level_notify(chr: &BlePeerCharacteristic) -> Result<(), BleError> {
+    match chr.can_notify() || chr.can_indicate() {
+        true => Ok(()),
+        false => Err(BleError::Unsupported("battery level notifications")),
+    }
+}
+
+// Decodes the Battery Level notifications before passing them on.
+struct BleBatteryLevelSender<S>(S);
+
+impl<S: BleEventSender<u8>> BleEventSender<Vec<u8>> for BleBatteryLevelSender<S> {
+    fn send_event(&self, data: Vec<u8>) -> bool {
+        match gatt_std::decode_battery_level(&data) {
+            Ok(level) => self.0.send_event(level),
+            Err(e) => {
+                log::warn!("Ignoring battery level notification: {}", e);
+                true
+            }
+        }
+    }
+}
+
 impl std::fmt::Display for BlePeerDevice {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -0,0 +1,318 @@
+// NimBLE error codes:
+// https://mynewt.apache.org/latest/network/ble_hs/ble_hs_return_codes.html
+
+use esp_idf_sys::EspError;
+
+#[derive(Debug, Clone)]
+pub enum BleError {
+    // Errors reported by the NimBLE host (BLE_HS_E*).
+    Again,
+    Already,
+    InvalidArgument,
+    MessageSize,
+    NotFound,
+    NoMemory,
+    NotConnected,
+    NotSupported,
+    ApplicationError,
+    BadData,
+    OsError,
+    ControllerError,
+    Busy,
+    Rejected,
+    UnknownError,
+    Role,
+    HciTimeout,
+    NoMemoryEvent,
+    NoAddress,
+    NotSynced,
+    Authentication,
+    Authorization,
+    Encryption,
+    EncryptionKeySize,
+    StoreCapacity,
+    StoreFailure,
+    Host(u16),
+    // Errors reported by the peer.
+    Att(BleAttError),
+    // HCI error codes, e.g. disconnect reasons.
+    Hci(u8),
+    L2cap(u8),
+    // Pairing failed on our side (peer == false) or the peer's.
+    SecurityManager { peer: bool, code: u8 },
+
+    // A blocking operation didn't get an answer in time, see BleTimeouts. GATT
+    // timeouts also terminate the connection.
+    Timeout(&'static str),
+    // The NimBLE procedure ended without reporting a result.
+    Aborted(&'static str),
+    // The Ble instance was dropped.
+    StackGone,
+    UnknownDevice,
+    Unsupported(&'static str),
+    Invalid(String),
+    Esp(EspError),
+}
+
+impl BleError {
+    // Maps NimBLE return codes (ble_hs.h) to errors, 0 isn't an error.
+    pub fn from_code(rc: u32) -> Option<Self> {
+        Some(match rc {
+            0 => return None,
+            esp_idf_sys::BLE_HS_EAGAIN => Self::Again,
+            esp_idf_sys::BLE_HS_EALREADY => Self::Already,
+            esp_idf_sys::BLE_HS_EINVAL => Self::InvalidArgument,
+            esp_idf_sys::BLE_HS_EMSGSIZE => Self::MessageSize,
+            esp_idf_sys::BLE_HS_ENOENT => Self::NotFound,
+            esp_idf_sys::BLE_HS_ENOMEM => Self::NoMemory,
+            esp_idf_sys::BLE_HS_ENOTCONN => Self::NotConnected,
+            esp_idf_sys::BLE_HS_ENOTSUP => Self::NotSupported,
+            esp_idf_sys::BLE_HS_EAPP => Self::ApplicationError,
+            esp_idf_sys::BLE_HS_EBADDATA => Self::BadData,
+            esp_idf_sys::BLE_HS_EOS => Self::OsError,
+            esp_idf_sys::BLE_HS_ECONTROLLER => Self::ControllerError,
+            esp_idf_sys::BLE_HS_ETIMEOUT => Self::Timeout("host"),
+            esp_idf_sys::BLE_HS_EBUSY => Self::Busy,
+            esp_idf_sys::BLE_HS_EREJECT => Self::Rejected,
+            esp_idf_sys::BLE_HS_EUNKNOWN => Self::UnknownError,
+            esp_idf_sys::BLE_HS_EROLE => Self::Role,
+            esp_idf_sys::BLE_HS_ETIMEOUT_HCI => Self::HciTimeout,
+            esp_idf_sys::BLE_HS_ENOMEM_EVT => Self::NoMemoryEvent,
+            esp_idf_sys::BLE_HS_ENOADDR => Self::NoAddress,
+            esp_idf_sys::BLE_HS_ENOTSYNCED => Self::NotSynced,
+            esp_idf_sys::BLE_HS_EAUTHEN => Self::Authentication,
+            esp_idf_sys::BLE_HS_EAUTHOR => Self::Authorization,
+            esp_idf_sys::BLE_HS_EENCRYPT => Self::Encryption,
+            esp_idf_sys::BLE_HS_EENCRYPT_KEY_SZ => Self::EncryptionKeySize,
+            esp_idf_sys::BLE_HS_ESTORE_CAP => Self::StoreCapacity,
+            esp_idf_sys::BLE_HS_ESTORE_FAIL => Self::StoreFailure,
+            rc if rc & 0xff00 == esp_idf_sys::BLE_HS_ERR_ATT_BASE => {
+                match BleAttError::from_status(rc as u16) {
+                    Some(e) => Self::Att(e),
+                    None => Self::Host(rc as u16),
+                }
+            }
+            rc if rc & 0xff00 == esp_idf_sys::BLE_HS_ERR_HCI_BASE => Self::Hci(rc as u8),
+            rc if rc & 0xff00 == esp_idf_sys::BLE_HS_ERR_L2C_BASE => Self::L2cap(rc as u8),
+            rc if rc & 0xff00 == esp_idf_sys::BLE_HS_ERR_SM_US_BASE => Self::SecurityManager {
+                peer: false,
+                code: rc as u8,
+            },
+            rc if rc & 0xff00 == esp_idf_sys::BLE_HS_ERR_SM_PEER_BASE => Self::SecurityManager {
+                peer: true,
+                code: rc as u8,
+            },
+            rc => Self::Host(rc as u16),
+        })
+    }
+
+    // Same as from_code but for the signed return codes of the NimBLE API.
+    pub fn check(rc: esp_idf_sys::c_types::c_int) -> Result<(), Self> {
+        match Self::from_code(rc as u32) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    // Errors that might go away by retrying the same operation later.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::Again
+                | Self::Busy
+                | Self::NoMemory
+                | Self::NoMemoryEvent
+                | Self::Timeout(_)
+                | Self::HciTimeout
+                | Self::Att(BleAttError::PrepareQueueFull)
+                | Self::Att(BleAttError::InsufficientResources)
+        )
+    }
+}
+
+impl std::fmt::Display for BleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Again => write!(f, "temporary failure, try again"),
+            Self::Already => write!(f, "operation already in progress or completed"),
+            Self::InvalidArgument => write!(f, "invalid argument"),
+            Self::MessageSize => write!(f, "data doesn't fit in the packet"),
+            Self::NotFound => write!(f, "no entry matching the request"),
+            Self::NoMemory => write!(f, "out of memory"),
+            Self::NotConnected => write!(f, "no open connection with that handle"),
+            Self::NotSupported => write!(f, "operation disabled at compile time"),
+            Self::ApplicationError => write!(f, "application callback behaved unexpectedly"),
+            Self::BadData => write!(f, "peer sent malformed data"),
+            Self::OsError => write!(f, "operating system error"),
+            Self::ControllerError => write!(f, "event from controller is invalid"),
+            Self::Busy => write!(f, "procedure already in progress"),
+            Self::Rejected => write!(f, "peer rejected a connection parameter update"),
+            Self::UnknownError => write!(f, "unexpected failure, catch all"),
+            Self::Role => write!(f, "operation requires a different role"),
+            Self::HciTimeout => write!(f, "HCI request timed out, controller unresponsive"),
+            Self::NoMemoryEvent => write!(f, "controller failed to send event, out of memory"),
+            Self::NoAddress => write!(f, "no identity address configured"),
+            Self::NotSynced => write!(f, "host not synced with the controller"),
+            Self::Authentication => write!(f, "insufficient authentication"),
+            Self::Authorization => write!(f, "insufficient authorization"),
+            Self::Encryption => write!(f, "insufficient encryption level"),
+            Self::EncryptionKeySize => write!(f, "insufficient key size"),
+            Self::StoreCapacity => write!(f, "bond store full"),
+            Self::StoreFailure => write!(f, "bond store operation failed"),
+            Self::Host(rc) => write!(f, "NimBLE error rc={}", rc),
+            Self::Att(e) => write!(f, "ATT error: {}", e),
+            Self::Hci(code) => write!(f, "HCI error: {}", hci_error_description(*code)),
+            Self::L2cap(code) => write!(f, "L2CAP error 0x{:02x}", code),
+            Self::SecurityManager { peer, code } => write!(
+                f,
+                "pairing failed ({}) reason=0x{:02x}",
+                if *peer { "peer" } else { "us" },
+                code
+            ),
+            Self::Timeout(operation) => write!(f, "{}: timed out waiting for the peer", operation),
+            Self::Aborted(operation) => write!(f, "{}: procedure aborted", operation),
+            Self::StackGone => write!(f, "BLE stack was dropped"),
+            Self::UnknownDevice => write!(f, "device isn't known by the BLE stack"),
+            Self::Unsupported(what) => write!(f, "not supported: {}", what),
+            Self::Invalid(what) => write!(f, "invalid: {}", what),
+            Self::Esp(e) => write!(f, "ESP-IDF error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BleError {}
+
+impl From<BleAttError> for BleError {
+    fn from(e: BleAttError) -> Self {
+        Self::Att(e)
+    }
+}
+
+impl From<EspError> for BleError {
+    fn from(e: EspError) -> Self {
+        Self::Esp(e)
+    }
+}
+
+// The most common ones, mostly seen as disconnect reasons.
+fn hci_error_description(code: u8) -> String {
+    match code as u32 {
+        esp_idf_sys::BLE_ERR_AUTH_FAIL => "authentication failure".to_owned(),
+        esp_idf_sys::BLE_ERR_PINKEY_MISSING => "PIN or key missing".to_owned(),
+        esp_idf_sys::BLE_ERR_CONN_SPVN_TMO => "connection timeout".to_owned(),
+        esp_idf_sys::BLE_ERR_CONN_ACCEPT_TMO => "connection accept timeout".to_owned(),
+        esp_idf_sys::BLE_ERR_REM_USER_CONN_TERM => "remote user terminated connection".to_owned(),
+        esp_idf_sys::BLE_ERR_RD_CONN_TERM_RESRCS => "remote device low resources".to_owned(),
+        esp_idf_sys::BLE_ERR_RD_CONN_TERM_PWROFF => "remote device powered off".to_owned(),
+        esp_idf_sys::BLE_ERR_CONN_TERM_LOCAL => "connection terminated by local host".to_owned(),
+        esp_idf_sys::BLE_ERR_LMP_LL_RSP_TMO => "LL response timeout".to_owned(),
+        esp_idf_sys::BLE_ERR_CONN_ESTABLISHMENT => "connection failed to be established".to_owned(),
+        esp_idf_sys::BLE_ERR_UNK_CONN_ID => "unknown connection identifier".to_owned(),
+        esp_idf_sys::BLE_ERR_CONN_LIMIT => "connection limit exceeded".to_owned(),
+        _ => format!("0x{:02x}", code),
+    }
+}
+
+// ATT error codes as reported by the peer. NimBLE reports them in the status
+// field of ble_gatt_error offset by BLE_HS_ERR_ATT_BASE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BleAttError {
+    InvalidHandle,
+    ReadNotPermitted,
+    WriteNotPermitted,
+    InvalidPdu,
+    InsufficientAuthentication,
+    RequestNotSupported,
+    InvalidOffset,
+    InsufficientAuthorization,
+    PrepareQueueFull,
+    AttributeNotFound,
+    AttributeNotLong,
+    InsufficientKeySize,
+    InvalidAttributeValueLength,
+    Unlikely,
+    InsufficientEncryption,
+    UnsupportedGroupType,
+    InsufficientResources,
+    Other(u8),
+}
+
+impl BleAttError {
+    pub fn from_status(status: u16) -> Option<Self> {
+        let base = esp_idf_sys::BLE_HS_ERR_ATT_BASE as u16;
+        if status <= base || status > base + 0xff {
+            return None;
+        }
+        Some(match (status - base) as u32 {
+            esp_idf_sys::BLE_ATT_ERR_INVALID_HANDLE => Self::InvalidHandle,
+            esp_idf_sys::BLE_ATT_ERR_READ_NOT_PERMITTED => Self::ReadNotPermitted,
+            esp_idf_sys::BLE_ATT_ERR_WRITE_NOT_PERMITTED => Self::WriteNotPermitted,
+            esp_idf_sys::BLE_ATT_ERR_INVALID_PDU => Self::InvalidPdu,
+            esp_idf_sys::BLE_ATT_ERR_INSUFFICIENT_AUTHEN => Self::InsufficientAuthentication,
+            esp_idf_sys::BLE_ATT_ERR_REQ_NOT_SUPPORTED => Self::RequestNotSupported,
+            esp_idf_sys::BLE_ATT_ERR_INVALID_OFFSET => Self::InvalidOffset,
+            esp_idf_sys::BLE_ATT_ERR_INSUFFICIENT_AUTHOR => Self::InsufficientAuthorization,
+            esp_idf_sys::BLE_ATT_ERR_PREPARE_QUEUE_FULL => Self::PrepareQueueFull,
+            esp_idf_sys::BLE_ATT_ERR_ATTR_NOT_FOUND => Self::AttributeNotFound,
+            esp_idf_sys::BLE_ATT_ERR_ATTR_NOT_LONG => Self::AttributeNotLong,
+            esp_idf_sys::BLE_ATT_ERR_INSUFFICIENT_KEY_SZ => Self::InsufficientKeySize,
+            esp_idf_sys::BLE_ATT_ERR_INVALID_ATTR_VALUE_LEN => Self::InvalidAttributeValueLength,
+            esp_idf_sys::BLE_ATT_ERR_UNLIKELY => Self::Unlikely,
+            esp_idf_sys::BLE_ATT_ERR_INSUFFICIENT_ENC => Self::InsufficientEncryption,
+            esp_idf_sys::BLE_ATT_ERR_UNSUPPORTED_GROUP => Self::UnsupportedGroupType,
+            esp_idf_sys::BLE_ATT_ERR_INSUFFICIENT_RES => Self::InsufficientResources,
+            code => Self::Other(code as u8),
+        })
+    }
+
+    // ATT error code as sent on the air.
+    pub fn code(&self) -> u8 {
+        (match self {
+            Self::InvalidHandle => esp_idf_sys::BLE_ATT_ERR_INVALID_HANDLE,
+            Self::ReadNotPermitted => esp_idf_sys::BLE_ATT_ERR_READ_NOT_PERMITTED,
+            Self::WriteNotPermitted => esp_idf_sys::BLE_ATT_ERR_WRITE_NOT_PERMITTED,
+            Self::InvalidPdu => esp_idf_sys::BLE_ATT_ERR_INVALID_PDU,
+            Self::InsufficientAuthentication => esp_idf_sys::BLE_ATT_ERR_INSUFFICIENT_AUTHEN,
+            Self::RequestNotSupported => esp_idf_sys::BLE_ATT_ERR_REQ_NOT_SUPPORTED,
+            Self::InvalidOffset => esp_idf_sys::BLE_ATT_ERR_INVALID_OFFSET,
+            Self::InsufficientAuthorization => esp_idf_sys::BLE_ATT_ERR_INSUFFICIENT_AUTHOR,
+            Self::PrepareQueueFull => esp_idf_sys::BLE_ATT_ERR_PREPARE_QUEUE_FULL,
+            Self::AttributeNotFound => esp_idf_sys::BLE_ATT_ERR_ATTR_NOT_FOUND,
+            Self::AttributeNotLong => esp_idf_sys::BLE_ATT_ERR_ATTR_NOT_LONG,
+            Self::InsufficientKeySize => esp_idf_sys::BLE_ATT_ERR_INSUFFICIENT_KEY_SZ,
+            Self::InvalidAttributeValueLength => esp_idf_sys::BLE_ATT_ERR_INVALID_ATTR_VALUE_LEN,
+            Self::Unlikely => esp_idf_sys::BLE_ATT_ERR_UNLIKELY,
+            Self::InsufficientEncryption => esp_idf_sys::BLE_ATT_ERR_INSUFFICIENT_ENC,
+            Self::UnsupportedGroupType => esp_idf_sys::BLE_ATT_ERR_UNSUPPORTED_GROUP,
+            Self::InsufficientResources => esp_idf_sys::BLE_ATT_ERR_INSUFFICIENT_RES,
+            Self::Other(code) => *code as u32,
+        }) as u8
+    }
+}
+
+impl std::fmt::Display for BleAttError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::InvalidHandle => write!(f, "invalid attribute handle"),
+            Self::ReadNotPermitted => write!(f, "attribute can't be read"),
+            Self::WriteNotPermitted => write!(f, "attribute can't be written"),
+            Self::InvalidPdu => write!(f, "invalid PDU"),
+            Self::InsufficientAuthentication => write!(f, "insufficient authentication"),
+            Self::RequestNotSupported => write!(f, "request not supported"),
+            Self::InvalidOffset => write!(f, "invalid offset"),
+            Self::InsufficientAuthorization => write!(f, "insufficient authorization"),
+            Self::PrepareQueueFull => write!(f, "prepare queue full"),
+            Self::AttributeNotFound => write!(f, "attribute not found"),
+            Self::AttributeNotLong => write!(f, "attribute can't be read using read blob"),
+            Self::InsufficientKeySize => write!(f, "insufficient encryption key size"),
+            Self::InvalidAttributeValueLength => write!(f, "invalid attribute value length"),
+            Self::Unlikely => write!(f, "unlikely error"),
+            Self::InsufficientEncryption => write!(f, "insufficient encryption"),
+            Self::UnsupportedGroupType => write!(f, "unsupported group type"),
+            Self::InsufficientResources => write!(f, "insufficient resources"),
+            Self::Other(code) => write!(f, "ATT error 0x{:02x}", code),
+        }
+    }
+}
+
+impl std::error::Error for BleAttError {}
@@ -0,0 +1,116 @@
+// Attribute tree of a peer, see BlePeerDevice::discover_all.
+
+use super::{
+    channel::BleReceiver,
+    chr::{BlePeerCharacteristic, BlePeerDescriptor, BleSubscription},
+    error::BleError,
+    gatt_std::{DATABASE_HASH_CHR_UUID, GATT_SVC_UUID, SERVICE_CHANGED_CHR_UUID},
+    svc::BlePeerService,
+    uuid::BleUUID,
+};
+use std::sync::mpsc::Receiver;
+
+#[derive(Clone)]
+pub struct BlePeerGatt {
+    pub services: Vec<BlePeerGattService>,
+}
+
+#[derive(Clone)]
+pub struct BlePeerGattService {
+    pub service: BlePeerService,
+    pub characteristics: Vec<BlePeerGattCharacteristic>,
+}
+
+#[derive(Clone)]
+pub struct BlePeerGattCharacteristic {
+    pub characteristic: BlePeerCharacteristic,
+    pub descriptors: Vec<BlePeerDescriptor>,
+}
+
+impl BlePeerGatt {
+    pub fn service(&self, uuid: &BleUUID) -> Option<&BlePeerGattService> {
+        self.services.iter().find(|svc| svc.service.uuid() == uuid)
+    }
+
+    pub fn characteristic(
+        &self,
+        service: &BleUUID,
+        characteristic: &BleUUID,
+    ) -> Option<&BlePeerGattCharacteristic> {
+        self.service(service)
+            .and_then(|svc| svc.characteristic(characteristic))
+    }
+
+    // The Service Changed characteristic of the GATT service, indicated when
+    // the attributes change.
+    pub(super) fn service_changed(&self) -> Option<&BlePeerGattCharacteristic> {
+        self.characteristic(&GATT_SVC_UUID, &SERVICE_CHANGED_CHR_UUID)
+    }
+
+    // Changes whenever the attributes do, on peers supporting GATT caching.
+    pub(super) fn database_hash(&self) -> Option<&BlePeerGattCharacteristic> {
+        self.characteristic(&GATT_SVC_UUID, &DATABASE_HASH_CHR_UUID)
+    }
+
+    // Same services at the same handles.
+    pub(super) fn has_services(&self, services: &[BlePeerService]) -> bool {
+        self.services.len() == services.len()
+            && self.services.iter().zip(services).all(|(a, b)| {
+                a.service.start_handle == b.start_handle
+                    && a.service.end_handle == b.end_handle
+                    && a.service.uuid == b.uuid
+            })
+    }
+}
+
+impl BlePeerGattService {
+    pub fn characteristic(&self, uuid: &BleUUID) -> Option<&BlePeerGattCharacteristic> {
+        self.characteristics
+            .iter()
+            .find(|chr| chr.characteristic.uuid() == uuid)
+    }
+}
+
+impl BlePeerGattCharacteristic {
+    pub fn descriptor(&self, uuid: &BleUUID) -> Option<&BlePeerDescriptor> {
+        self.descriptors.iter().find(|dsc| dsc.uuid() == uuid)
+    }
+
+    // Same as BlePeerCharacteristic::set_subscription, without discovering
+    // the descriptors again.
+    pub fn set_subscription(&self, mode: BleSubscription) -> Result<(), BleError> {
+        self.characteristic
+            .set_subscription_with(mode, &self.descriptors)
+    }
+
+    pub async fn set_subscription_async(&self, mode: BleSubscription) -> Result<(), BleError> {
+        self.characteristic
+            .set_subscription_with_async(mode, &self.descriptors)
+            .await
+    }
+
+    // Same as BlePeerCharacteristic::subscribe, without discovering the
+    // descriptors again.
+    pub fn subscribe(&self) -> Result<Receiver<Vec<u8>>, BleError> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let id = self.characteristic.add_subscriber(Box::new(tx))?;
+        if let Err(e) = self.set_subscription(self.characteristic.notify_mode(true)) {
+            self.characteristic.remove_subscriber(id);
+            return Err(e);
+        }
+        Ok(rx)
+    }
+
+    pub async fn subscribe_async(&self) -> Result<BleReceiver<Vec<u8>>, BleError> {
+        let (tx, rx) = super::channel::channel();
+        let id = self.characteristic.add_subscriber(Box::new(tx))?;
+        if let Err(e) = self
+            .set_subscription_async(self.characteristic.notify_mode(true))
+            .await
+        {
+            self.characteristic.remove_subscriber(id);
+            return Err(e);
+        }
+        Ok(rx)
+    }
+}
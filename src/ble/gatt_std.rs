@@ -0,0 +1,150 @@
+// UUIDs assigned by the Bluetooth SIG and decoders for the values of the most
+// common characteristics.
+// https://www.bluetooth.com/specifications/assigned-numbers/
+// https://www.bluetooth.com/specifications/specs/gatt-specification-supplement/
+
+use super::{error::BleError, uuid::BleUUID};
+
+// Attribute types.
+pub const PRIMARY_SERVICE_UUID: BleUUID = BleUUID::from_u16(0x2800);
+pub const SECONDARY_SERVICE_UUID: BleUUID = BleUUID::from_u16(0x2801);
+pub const INCLUDE_UUID: BleUUID = BleUUID::from_u16(0x2802);
+pub const CHR_DECLARATION_UUID: BleUUID = BleUUID::from_u16(0x2803);
+
+// Services.
+pub const GAP_SVC_UUID: BleUUID = BleUUID::from_u16(0x1800);
+pub const GATT_SVC_UUID: BleUUID = BleUUID::from_u16(0x1801);
+pub const IMMEDIATE_ALERT_SVC_UUID: BleUUID = BleUUID::from_u16(0x1802);
+pub const TX_POWER_SVC_UUID: BleUUID = BleUUID::from_u16(0x1804);
+pub const CURRENT_TIME_SVC_UUID: BleUUID = BleUUID::from_u16(0x1805);
+pub const DEVICE_INFORMATION_SVC_UUID: BleUUID = BleUUID::from_u16(0x180a);
+pub const HEART_RATE_SVC_UUID: BleUUID = BleUUID::from_u16(0x180d);
+pub const BATTERY_SVC_UUID: BleUUID = BleUUID::from_u16(0x180f);
+pub const HID_SVC_UUID: BleUUID = BleUUID::from_u16(0x1812);
+pub const ENVIRONMENTAL_SENSING_SVC_UUID: BleUUID = BleUUID::from_u16(0x181a);
+
+// Characteristics.
+pub const DEVICE_NAME_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a00);
+pub const APPEARANCE_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a01);
+pub const SERVICE_CHANGED_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a05);
+pub const ALERT_LEVEL_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a06);
+pub const TX_POWER_LEVEL_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a07);
+pub const BATTERY_LEVEL_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a19);
+pub const SYSTEM_ID_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a23);
+pub const MODEL_NUMBER_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a24);
+pub const SERIAL_NUMBER_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a25);
+pub const FIRMWARE_REVISION_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a26);
+pub const HARDWARE_REVISION_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a27);
+pub const SOFTWARE_REVISION_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a28);
+pub const MANUFACTURER_NAME_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a29);
+pub const CURRENT_TIME_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a2b);
+pub const HEART_RATE_MEASUREMENT_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a37);
+pub const BODY_SENSOR_LOCATION_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a38);
+pub const HID_INFORMATION_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a4a);
+pub const REPORT_MAP_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a4b);
+pub const HID_CONTROL_POINT_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a4c);
+pub const REPORT_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a4d);
+pub const PROTOCOL_MODE_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a4e);
+pub const PNP_ID_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a50);
+pub const PRESSURE_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a6d);
+pub const TEMPERATURE_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a6e);
+pub const HUMIDITY_CHR_UUID: BleUUID = BleUUID::from_u16(0x2a6f);
+pub const DATABASE_HASH_CHR_UUID: BleUUID = BleUUID::from_u16(0x2b2a);
+
+// Descriptors.
+pub const EXTENDED_PROPERTIES_DSC_UUID: BleUUID = BleUUID::from_u16(0x2900);
+pub const USER_DESCRIPTION_DSC_UUID: BleUUID = BleUUID::from_u16(0x2901);
+// Client Characteristic Configuration Descriptor.
+pub const CCCD_UUID: BleUUID = BleUUID::from_u16(0x2902);
+pub const SCCD_UUID: BleUUID = BleUUID::from_u16(0x2903);
+pub const PRESENTATION_FORMAT_DSC_UUID: BleUUID = BleUUID::from_u16(0x2904);
+pub const REPORT_REFERENCE_DSC_UUID: BleUUID = BleUUID::from_u16(0x2908);
+pub const ENVIRONMENTAL_SENSING_MEASUREMENT_DSC_UUID: BleUUID = BleUUID::from_u16(0x290c);
+
+// Who assigned BlePnpId::vendor_id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BleVendorIdSource {
+    BluetoothSig,
+    UsbImplementersForum,
+    Unknown(u8),
+}
+
+impl From<u8> for BleVendorIdSource {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::BluetoothSig,
+            2 => Self::UsbImplementersForum,
+            value => Self::Unknown(value),
+        }
+    }
+}
+
+// Value of the PnP ID characteristic of the Device Information service.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlePnpId {
+    pub vendor_id_source: BleVendorIdSource,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub product_version: u16,
+}
+
+// Charge left, in percent.
+pub fn decode_battery_level(data: &[u8]) -> Result<u8, BleError> {
+    match data {
+        [level] if *level <= 100 => Ok(*level),
+        _ => Err(invalid("battery level", data)),
+    }
+}
+
+pub fn decode_pnp_id(data: &[u8]) -> Result<BlePnpId, BleError> {
+    match data {
+        [source, v0, v1, p0, p1, r0, r1] => Ok(BlePnpId {
+            vendor_id_source: BleVendorIdSource::from(*source),
+            vendor_id: u16::from_le_bytes([*v0, *v1]),
+            product_id: u16::from_le_bytes([*p0, *p1]),
+            product_version: u16::from_le_bytes([*r0, *r1]),
+        }),
+        _ => Err(invalid("PnP ID", data)),
+    }
+}
+
+// Manufacturer name, model and serial numbers, revisions and the device name
+// are UTF-8 strings. Some peers pad them with nulls.
+pub fn decode_string(data: &[u8]) -> String {
+    let end = data.iter().rposition(|c| *c != 0).map_or(0, |i| i + 1);
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+// Degrees Celsius, None if the sensor doesn't know.
+pub fn decode_temperature(data: &[u8]) -> Result<Option<f32>, BleError> {
+    match data {
+        [a, b] => match i16::from_le_bytes([*a, *b]) {
+            i16::MIN => Ok(None),
+            value => Ok(Some(value as f32 / 100.0)),
+        },
+        _ => Err(invalid("temperature", data)),
+    }
+}
+
+// Relative humidity in percent, None if the sensor doesn't know.
+pub fn decode_humidity(data: &[u8]) -> Result<Option<f32>, BleError> {
+    match data {
+        [a, b] => match u16::from_le_bytes([*a, *b]) {
+            u16::MAX => Ok(None),
+            value => Ok(Some(value as f32 / 100.0)),
+        },
+        _ => Err(invalid("humidity", data)),
+    }
+}
+
+// Pascals.
+pub fn decode_pressure(data: &[u8]) -> Result<f32, BleError> {
+    match data {
+        [a, b, c, d] => Ok(u32::from_le_bytes([*a, *b, *c, *d]) as f32 / 10.0),
+        _ => Err(invalid("pressure", data)),
+    }
+}
+
+fn invalid(what: &str, data: &[u8]) -> BleError {
+    BleError::Invalid(format!("{} value {:02x?}", what, data))
+}
@@ -0,0 +1,231 @@
+// Attribute trees of bonded peers kept in NVS, so reconnecting to them
+// doesn't need a full discovery, see Ble::set_gatt_store. Entries are keyed
+// by identity address and dropped when the peer indicates that its services
+// changed, when they don't match the peer anymore or the bond is deleted.
+
+use super::{
+    chr::{BlePeerCharacteristic, BlePeerDescriptor},
+    dev::{BleConnHandle, BlePeerDeviceAddress},
+    error::BleError,
+    gatt::{BlePeerGatt, BlePeerGattCharacteristic, BlePeerGattService},
+    svc::BlePeerService,
+    uuid::BleUUID,
+    Ble,
+};
+use embedded_svc::storage::Storage;
+use esp_idf_hal::mutex::Mutex;
+use esp_idf_svc::{nvs::EspDefaultNvs, nvs_storage::EspNvsStorage};
+use esp_idf_sys::{
+    ble_uuid128_t, ble_uuid16_t, ble_uuid32_t, ble_uuid_any_t, ble_uuid_t, BLE_UUID_TYPE_128,
+    BLE_UUID_TYPE_16, BLE_UUID_TYPE_32,
+};
+use std::sync::{Arc, Weak};
+
+const NAMESPACE: &str = "ble_gatt";
+// Bumped when the layout changes, entries of other versions are ignored.
+const VERSION: u8 = 2;
+
+pub(super) struct BleStoredGatt {
+    pub(super) gatt: BlePeerGatt,
+    // Value of the Database Hash characteristic when the tree was discovered,
+    // if the peer has one.
+    pub(super) database_hash: Option<Vec<u8>>,
+}
+
+pub(super) fn load(
+    nvs: Arc<EspDefaultNvs>,
+    address: &BlePeerDeviceAddress,
+    conn_handle: BleConnHandle,
+    ble: Weak<Mutex<Ble>>,
+) -> Result<Option<BleStoredGatt>, BleError> {
+    let data = match storage(nvs)?.get_raw(&key(address))? {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+    let stored = decode(&data, conn_handle, ble);
+    if stored.is_none() {
+        log::warn!("BLE: ignoring the stored GATT database of {}", address);
+    }
+    Ok(stored)
+}
+
+pub(super) fn save(
+    nvs: Arc<EspDefaultNvs>,
+    address: &BlePeerDeviceAddress,
+    gatt: &BlePeerGatt,
+    database_hash: Option<&[u8]>,
+) -> Result<(), BleError> {
+    storage(nvs)?.put_raw(&key(address), encode(gatt, database_hash))?;
+    Ok(())
+}
+
+pub(super) fn remove(
+    nvs: Arc<EspDefaultNvs>,
+    address: &BlePeerDeviceAddress,
+) -> Result<(), BleError> {
+    storage(nvs)?.remove(&key(address))?;
+    Ok(())
+}
+
+fn storage(nvs: Arc<EspDefaultNvs>) -> Result<EspNvsStorage, BleError> {
+    Ok(EspNvsStorage::new_default(nvs, NAMESPACE, true)?)
+}
+
+// NVS keys are up to 15 characters long.
+fn key(address: &BlePeerDeviceAddress) -> String {
+    let val = &address.0.val;
+    format!(
+        "{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}{}",
+        val[5], val[4], val[3], val[2], val[1], val[0], address.0.type_
+    )
+}
+
+// Little endian handles, every list prefixed by its length.
+fn encode(gatt: &BlePeerGatt, database_hash: Option<&[u8]>) -> Vec<u8> {
+    let mut data = vec![VERSION];
+    let database_hash = database_hash.unwrap_or_default();
+    data.push(database_hash.len() as u8);
+    data.extend(database_hash);
+    put_u16(&mut data, gatt.services.len() as u16);
+    for svc in &gatt.services {
+        put_u16(&mut data, svc.service.start_handle);
+        put_u16(&mut data, svc.service.end_handle);
+        put_uuid(&mut data, &svc.service.uuid);
+        put_u16(&mut data, svc.characteristics.len() as u16);
+        for chr in &svc.characteristics {
+            put_u16(&mut data, chr.characteristic.def_handle);
+            put_u16(&mut data, chr.characteristic.val_handle);
+            put_u16(&mut data, chr.characteristic.end_handle);
+            data.push(chr.characteristic.properties);
+            put_uuid(&mut data, &chr.characteristic.uuid);
+            put_u16(&mut data, chr.descriptors.len() as u16);
+            for dsc in &chr.descriptors {
+                put_u16(&mut data, dsc.handle);
+                put_uuid(&mut data, &dsc.uuid);
+            }
+        }
+    }
+    data
+}
+
+fn put_u16(data: &mut Vec<u8>, value: u16) {
+    data.extend(value.to_le_bytes());
+}
+
+fn put_uuid(data: &mut Vec<u8>, uuid: &BleUUID) {
+    let native = uuid.native();
+    unsafe {
+        data.push(native.u.type_);
+        match native.u.type_ as u32 {
+            BLE_UUID_TYPE_16 => data.extend(native.u16_.value.to_le_bytes()),
+            BLE_UUID_TYPE_32 => data.extend(native.u32_.value.to_le_bytes()),
+            _ => data.extend(native.u128_.value),
+        }
+    }
+}
+
+// None if the data is malformed or from another version.
+fn decode(data: &[u8], conn_handle: BleConnHandle, ble: Weak<Mutex<Ble>>) -> Option<BleStoredGatt> {
+    let mut reader = BleGattReader(data);
+    if reader.u8()? != VERSION {
+        return None;
+    }
+    let database_hash = match reader.u8()? {
+        0 => None,
+        len => Some(reader.bytes(len as usize)?.to_vec()),
+    };
+    let mut services = vec![];
+    for _ in 0..reader.u16()? {
+        let service = BlePeerService {
+            conn_handle: conn_handle as u16,
+            start_handle: reader.u16()?,
+            end_handle: reader.u16()?,
+            uuid: reader.uuid()?,
+            ble: ble.clone(),
+        };
+        let mut characteristics = vec![];
+        for _ in 0..reader.u16()? {
+            let characteristic = BlePeerCharacteristic {
+                conn_handle,
+                def_handle: reader.u16()?,
+                val_handle: reader.u16()?,
+                end_handle: reader.u16()?,
+                properties: reader.u8()?,
+                uuid: reader.uuid()?,
+                ble: ble.clone(),
+            };
+            let mut descriptors = vec![];
+            for _ in 0..reader.u16()? {
+                descriptors.push(BlePeerDescriptor {
+                    conn_handle,
+                    chr_val_handle: characteristic.val_handle,
+                    handle: reader.u16()?,
+                    uuid: reader.uuid()?,
+                });
+            }
+            characteristics.push(BlePeerGattCharacteristic {
+                characteristic,
+                descriptors,
+            });
+        }
+        services.push(BlePeerGattService {
+            service,
+            characteristics,
+        });
+    }
+    match reader.0.is_empty() {
+        true => Some(BleStoredGatt {
+            gatt: BlePeerGatt { services },
+            database_hash,
+        }),
+        false => None,
+    }
+}
+
+struct BleGattReader<'a>(&'a [u8]);
+
+impl<'a> BleGattReader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (value, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(value)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn uuid(&mut self) -> Option<BleUUID> {
+        let type_ = self.u8()?;
+        let u = ble_uuid_t { type_ };
+        let native = match type_ as u32 {
+            BLE_UUID_TYPE_16 => ble_uuid_any_t {
+                u16_: ble_uuid16_t {
+                    u,
+                    value: self.u16()?,
+                },
+            },
+            BLE_UUID_TYPE_32 => ble_uuid_any_t {
+                u32_: ble_uuid32_t {
+                    u,
+                    value: u32::from_le_bytes(self.bytes(4)?.try_into().ok()?),
+                },
+            },
+            BLE_UUID_TYPE_128 => ble_uuid_any_t {
+                u128_: ble_uuid128_t {
+                    u,
+                    value: self.bytes(16)?.try_into().ok()?,
+                },
+            },
+            _ => return None,
+        };
+        Some(BleUUID::from(native))
+    }
+}
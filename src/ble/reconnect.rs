@@ -0,0 +1,250 @@
+// Keeps the connection to a known peer alive. Reconnects with exponential
+// backoff, straight to the peer's address (no scan needed), and subscribes
+// again to the characteristics it was subscribed to. Connects are direct and
+// leave the filter accept list alone, so several supervisors can share the
+// stack: NimBLE runs one connect at a time, the others fail and retry later.
+
+use super::{
+    client::{BleClient, BleConnectOptions, BleConnectionEvent},
+    dev::{BleConnHandle, BlePeerDevice, BlePeerDeviceAddress},
+    error::BleError,
+    uuid::BleUUID,
+    SafeBle,
+};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc::{Receiver, RecvTimeoutError, Sender},
+    Arc,
+};
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug)]
+pub struct BleReconnectPolicy {
+    // Delay after the first failure, doubled after each one after that.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    // Up to this fraction of the delay (0 to 1) is added at random, so peers
+    // that dropped at the same time don't retry in lockstep.
+    pub jitter: f32,
+    // Failed attempts in a row before giving up, None retries forever.
+    pub max_attempts: Option<u32>,
+    pub connect_options: BleConnectOptions,
+}
+
+impl BleReconnectPolicy {
+    // Delay before the next attempt after this many failures in a row.
+    fn delay(&self, failures: u32) -> Duration {
+        let delay = self
+            .initial_delay
+            .saturating_mul(1 << failures.saturating_sub(1).min(16))
+            .min(self.max_delay);
+        let jitter_ms = (delay.as_millis() as f32 * self.jitter.clamp(0.0, 1.0)) as u32;
+        let jitter_ms = match jitter_ms {
+            0 => 0,
+            jitter_ms => (unsafe { esp_idf_sys::esp_random() }) % (jitter_ms + 1),
+        };
+        delay + Duration::from_millis(jitter_ms as u64)
+    }
+}
+
+impl Default for BleReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.5,
+            max_attempts: None,
+            connect_options: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BleReconnectState {
+    // Attempt number since the last successful connection.
+    Connecting(u32),
+    // Connected, and subscriptions restored.
+    Connected(BleConnHandle),
+    // The connection dropped, or the attempt failed.
+    Disconnected(BleError),
+    // Until the next attempt.
+    Waiting(Duration),
+    // Stopped, or gave up after max_attempts.
+    Stopped,
+}
+
+struct BleReconnectSubscription {
+    service: BleUUID,
+    characteristic: BleUUID,
+    tx: Sender<Vec<u8>>,
+}
+
+pub struct BleReconnect {
+    ble: SafeBle,
+    address: BlePeerDeviceAddress,
+    policy: BleReconnectPolicy,
+    subscriptions: Vec<BleReconnectSubscription>,
+    on_state_change: Box<dyn FnMut(BleReconnectState) + Send>,
+}
+
+impl BleReconnect {
+    pub fn new(ble: SafeBle, address: BlePeerDeviceAddress, policy: BleReconnectPolicy) -> Self {
+        Self {
+            ble,
+            address,
+            policy,
+            subscriptions: vec![],
+            on_state_change: Box::new(|_| {}),
+        }
+    }
+
+    // Called from the supervisor thread.
+    pub fn on_state_change(
+        mut self,
+        callback: impl FnMut(BleReconnectState) + Send + 'static,
+    ) -> Self {
+        self.on_state_change = Box::new(callback);
+        self
+    }
+
+    // Values of the characteristic, from every connection: the channel stays
+    // open across reconnects and is closed once the supervisor stops.
+    pub fn subscribe(&mut self, service: BleUUID, characteristic: BleUUID) -> Receiver<Vec<u8>> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.subscriptions.push(BleReconnectSubscription {
+            service,
+            characteristic,
+            tx,
+        });
+        rx
+    }
+
+    // Connects and keeps reconnecting from a thread of its own.
+    pub fn start(self) -> Result<BleReconnectHandle, BleError> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = BleReconnectHandle { stop: stop.clone() };
+        std::thread::Builder::new()
+            .stack_size(8192)
+            .spawn(move || self.run(&stop))
+            .map_err(|_| BleError::NoMemory)?;
+        Ok(handle)
+    }
+
+    fn run(mut self, stop: &AtomicBool) {
+        let mut client = BleClient::new(self.ble.clone());
+        let mut failures = 0;
+        while !stop.load(Ordering::Relaxed) {
+            (self.on_state_change)(BleReconnectState::Connecting(failures + 1));
+            let reason = match self.connect(&mut client) {
+                Ok(device) => {
+                    failures = 0;
+                    if let Some(conn_handle) = device.conn_handle() {
+                        (self.on_state_change)(BleReconnectState::Connected(conn_handle));
+                    }
+                    self.wait_disconnect(&client, &device, stop)
+                }
+                Err(e) => {
+                    failures += 1;
+                    e
+                }
+            };
+            log::warn!("BLE reconnect: {} disconnected: {}", self.address, reason);
+            (self.on_state_change)(BleReconnectState::Disconnected(reason));
+
+            if matches!(self.policy.max_attempts, Some(max) if failures >= max) {
+                log::error!("BLE reconnect: giving up on {}", self.address);
+                break;
+            }
+            if stop.load(Ordering::Relaxed) {
+                break;
+            }
+            let delay = self.policy.delay(failures);
+            (self.on_state_change)(BleReconnectState::Waiting(delay));
+            let resume_ms = crate::get_time_millis() + delay.as_millis() as i64;
+            while !stop.load(Ordering::Relaxed) && crate::get_time_millis() < resume_ms {
+                crate::delay_ms(100);
+            }
+        }
+        (self.on_state_change)(BleReconnectState::Stopped);
+    }
+
+    fn connect(&self, client: &mut BleClient) -> Result<BlePeerDevice, BleError> {
+        // The device might have been forgotten while disconnected.
+        let mut device = self.ble.lock().device(&self.address);
+        client.connect_with_options(&device, &self.policy.connect_options)?;
+        if let Err(e) = self.restore_subscriptions(&mut device) {
+            log::error!("BLE reconnect: restoring subscriptions failed: {}", e);
+            client.disconnect(device.address()).ok();
+            return Err(e);
+        }
+        Ok(device)
+    }
+
+    fn restore_subscriptions(&self, device: &mut BlePeerDevice) -> Result<(), BleError> {
+        if self.subscriptions.is_empty() {
+            return Ok(());
+        }
+        // Loaded from NVS for bonded peers (see Ble::set_gatt_store), instead
+        // of discovering every service and characteristic again.
+        let gatt = device.discover_all()?;
+        for subscription in &self.subscriptions {
+            let chr = gatt
+                .characteristic(&subscription.service, &subscription.characteristic)
+                .ok_or(BleError::NotFound)?;
+            chr.characteristic
+                .add_subscriber(Box::new(subscription.tx.clone()))?;
+            chr.set_subscription(chr.characteristic.notify_mode(true))?;
+        }
+        Ok(())
+    }
+
+    // Returns the disconnect reason.
+    fn wait_disconnect(
+        &self,
+        client: &BleClient,
+        device: &BlePeerDevice,
+        stop: &AtomicBool,
+    ) -> BleError {
+        let mut reason = BleError::NotConnected;
+        let mut stopping = false;
+        let result = device.use_events_channel(|event_rx| loop {
+            match event_rx.recv_timeout(Duration::from_millis(100)) {
+                Ok(BleConnectionEvent::Disconnected(_, e)) => {
+                    reason = e;
+                    break;
+                }
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => {
+                    if stop.load(Ordering::Relaxed) && !stopping {
+                        stopping = true;
+                        client.disconnect(device.address()).ok();
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        });
+        match result {
+            Ok(()) => reason,
+            Err(e) => e,
+        }
+    }
+}
+
+pub struct BleReconnectHandle {
+    stop: Arc<AtomicBool>,
+}
+
+impl BleReconnectHandle {
+    // Drops the connection and stops reconnecting. The supervisor thread ends
+    // shortly after, reporting BleReconnectState::Stopped. Dropping the handle
+    // does the same.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for BleReconnectHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
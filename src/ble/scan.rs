@@ -1,110 +1,337 @@
 use super::{
+    adv::BleAdvertisementData,
+    channel::{BleEventSender, BleReceiver},
     dev::{BlePeerDevice, BlePeerDeviceAddress},
-    BlePeerDeviceSharedState, SafeBle,
+    error::BleError,
+    uuid::BleUUID,
+    Ble, SafeBle,
 };
-use anyhow::Result;
+use esp_idf_hal::mutex::Mutex;
 use std::sync::{
     mpsc::{Receiver, Sender},
-    Arc,
+    Arc, Weak,
 };
 
-enum BlePeerDeviceDiscoveryEvent {
-    Discovery(String, BlePeerDeviceAddress),
-    DiscoveryFinished,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BleAdvertisementType {
+    ConnectableUndirected,
+    ConnectableDirected,
+    ScannableUndirected,
+    NonConnectable,
+    ScanResponse,
+    Unknown(u8),
+}
+
+impl From<u8> for BleAdvertisementType {
+    fn from(event_type: u8) -> Self {
+        match event_type as u32 {
+            esp_idf_sys::BLE_HCI_ADV_RPT_EVTYPE_ADV_IND => Self::ConnectableUndirected,
+            esp_idf_sys::BLE_HCI_ADV_RPT_EVTYPE_DIR_IND => Self::ConnectableDirected,
+            esp_idf_sys::BLE_HCI_ADV_RPT_EVTYPE_SCAN_IND => Self::ScannableUndirected,
+            esp_idf_sys::BLE_HCI_ADV_RPT_EVTYPE_NONCONN_IND => Self::NonConnectable,
+            esp_idf_sys::BLE_HCI_ADV_RPT_EVTYPE_SCAN_RSP => Self::ScanResponse,
+            _ => Self::Unknown(event_type),
+        }
+    }
+}
+
+// Last advertisement (or scan response) received from a device.
+#[derive(Clone)]
+pub struct BleAdvertisementReport {
+    pub event_type: BleAdvertisementType,
+    pub rssi: i8,
+    pub data: BleAdvertisementData,
+}
+
+// Devices must match every configured criteria to be reported. Criteria that
+// take several values match if any of them does.
+#[derive(Clone, Default)]
+pub struct BleScanFilter {
+    name_prefix: Option<String>,
+    service_uuids: Vec<BleUUID>,
+    addresses: Vec<BlePeerDeviceAddress>,
+    min_rssi: Option<i8>,
+}
+
+impl BleScanFilter {
+    pub fn matches(&self, address: &BlePeerDeviceAddress, report: &BleAdvertisementReport) -> bool {
+        if let Some(prefix) = &self.name_prefix {
+            match &report.data.name {
+                Some(name) if name.starts_with(prefix.as_str()) => {}
+                _ => return false,
+            }
+        }
+        if !self.service_uuids.is_empty()
+            && !report
+                .data
+                .service_uuids
+                .iter()
+                .any(|uuid| self.service_uuids.contains(uuid))
+        {
+            return false;
+        }
+        if !self.addresses.is_empty() && !self.addresses.contains(address) {
+            return false;
+        }
+        if let Some(min_rssi) = self.min_rssi {
+            if report.rssi < min_rssi {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+pub struct BleScanBuilder {
+    ble: SafeBle,
+    active: bool,
+    // NimBLE defaults if not set.
+    interval_ms: Option<u32>,
+    window_ms: Option<u32>,
+    duration_ms: i32,
+    filter_duplicates: bool,
+    filter: BleScanFilter,
+}
+
+impl BleScanBuilder {
+    // Active scans send scan requests, so scan responses (which usually carry
+    // the name) are reported too.
+    pub fn active(mut self, active: bool) -> Self {
+        self.active = active;
+        self
+    }
+
+    // 2.5ms to 10.24s, checked by build.
+    pub fn interval_ms(mut self, interval_ms: u32) -> Self {
+        self.interval_ms = Some(interval_ms);
+        self
+    }
+
+    // 2.5ms to 10.24s and no longer than the interval, checked by build.
+    pub fn window_ms(mut self, window_ms: u32) -> Self {
+        self.window_ms = Some(window_ms);
+        self
+    }
+
+    // The scan runs until stopped if no duration is set.
+    pub fn duration_ms(mut self, duration_ms: u32) -> Self {
+        self.duration_ms = duration_ms.try_into().unwrap_or(i32::MAX);
+        self
+    }
+
+    pub fn filter_duplicates(mut self, filter_duplicates: bool) -> Self {
+        self.filter_duplicates = filter_duplicates;
+        self
+    }
+
+    pub fn name_prefix(mut self, prefix: &str) -> Self {
+        self.filter.name_prefix = Some(prefix.to_owned());
+        self
+    }
+
+    pub fn service_uuid(mut self, uuid: BleUUID) -> Self {
+        self.filter.service_uuids.push(uuid);
+        self
+    }
+
+    pub fn address(mut self, address: BlePeerDeviceAddress) -> Self {
+        self.filter.addresses.push(address);
+        self
+    }
+
+    pub fn min_rssi(mut self, rssi: i8) -> Self {
+        self.filter.min_rssi = Some(rssi);
+        self
+    }
+
+    pub fn build(self) -> Result<BleScan, BleError> {
+        let interval = match self.interval_ms {
+            Some(ms) => super::interval_units("scan interval", ms, SCAN_INTERVAL_RANGE)?,
+            None => esp_idf_sys::BLE_GAP_SCAN_SLOW_INTERVAL1 as u16,
+        };
+        let window = match self.window_ms {
+            Some(ms) => super::interval_units("scan window", ms, SCAN_INTERVAL_RANGE)?,
+            None => esp_idf_sys::BLE_GAP_SCAN_SLOW_WINDOW1 as u16,
+        };
+        if window > interval {
+            return Err(BleError::Invalid(format!(
+                "scan window ({} units) longer than the interval ({} units)",
+                window, interval
+            )));
+        }
+        Ok(self.build_with(interval, window))
+    }
+
+    // Interval and window in 0.625ms units.
+    fn build_with(self, interval: u16, window: u16) -> BleScan {
+        let (scan_tx, scan_rx) = std::sync::mpsc::channel();
+        let mut disc_params = esp_idf_sys::ble_gap_disc_params {
+            itvl: interval,
+            window,
+            filter_policy: esp_idf_sys::BLE_HCI_SCAN_FILT_NO_WL as u8,
+            ..Default::default()
+        };
+        disc_params.set_filter_duplicates(self.filter_duplicates as u8);
+        disc_params.set_passive(!self.active as u8);
+        disc_params.set_limited(0);
+        BleScan {
+            ble: self.ble,
+            disc_params,
+            duration_ms: self.duration_ms,
+            filter: self.filter,
+            session_id: None,
+            scan_rx,
+            scan_tx,
+        }
+    }
+}
+
+// In 0.625ms units.
+const SCAN_INTERVAL_RANGE: std::ops::RangeInclusive<u32> = 0x0004..=0x4000;
+
+// The scan NimBLE reports to. NimBLE runs one scan at a time and only gets the
+// session id as cb_arg, so a scan that was stopped or completed is never
+// reported to again, and a BleScan never cancels a scan it didn't start. Locked
+// before Ble.
+static SESSIONS: Mutex<BleScanSessions> = Mutex::new(BleScanSessions {
+    next_id: 1,
+    current: None,
+});
+
+struct BleScanSessions {
+    next_id: usize,
+    current: Option<BleScanSession>,
+}
+
+// Dropped when the scan ends, which closes the async channel.
+struct BleScanSession {
+    id: usize,
+    ble: Weak<Mutex<Ble>>,
+    filter: BleScanFilter,
+    scan_tx: Box<dyn BleEventSender<BlePeerDevice> + Send>,
+}
+
+impl BleScanSession {
+    // Reports the device to scan_tx if it matches the filter.
+    fn report(&self, address: BlePeerDeviceAddress, report: BleAdvertisementReport) {
+        match self.ble.upgrade() {
+            Some(ble) => {
+                // Filters are checked against the merged data, since with
+                // active scans part of it arrives in scan responses.
+                let report = ble.lock().update_device(&address, report);
+                if self.filter.matches(&address, &report) {
+                    self.scan_tx
+                        .send_event(BlePeerDevice::new(address, Arc::downgrade(&ble)));
+                }
+            }
+            // The stack is being dropped, nothing to report to.
+            None => log::warn!("BLE scan: discovery event after the stack was dropped"),
+        }
+    }
 }
 
 pub struct BleScan {
     ble: SafeBle,
     disc_params: esp_idf_sys::ble_gap_disc_params,
-    callback: Box<dyn Fn(BlePeerDeviceDiscoveryEvent)>,
+    duration_ms: i32,
+    filter: BleScanFilter,
+    // Id of the last session started, see SESSIONS.
+    session_id: Option<usize>,
     scan_tx: Sender<BlePeerDevice>,
     scan_rx: Receiver<BlePeerDevice>,
 }
 
 impl BleScan {
     pub fn new(ble: SafeBle) -> Self {
-        let (scan_tx, scan_rx) = std::sync::mpsc::channel();
-        let mut scan = Self {
-            ble,
-            disc_params: esp_idf_sys::ble_gap_disc_params {
-                ..Default::default()
-            },
-            callback: Box::new(|_| {}),
-            scan_rx,
-            scan_tx,
-        };
-        scan.set_default_disc_config();
-        scan
+        BleScan::builder(ble).build_with(
+            esp_idf_sys::BLE_GAP_SCAN_SLOW_INTERVAL1 as u16,
+            esp_idf_sys::BLE_GAP_SCAN_SLOW_WINDOW1 as u16,
+        )
     }
 
-    fn set_default_disc_config(&mut self) {
-        // Tell the controller to filter duplicates; we don't want to process
-        // repeated advertisements from the same device.
-        self.disc_params.set_filter_duplicates(1);
-
-        // Perform a passive scan.  I.e., don't send follow-up scan requests to
-        // each advertiser.
-        self.disc_params.set_passive(1);
+    pub fn builder(ble: SafeBle) -> BleScanBuilder {
+        BleScanBuilder {
+            ble,
+            // Perform a passive scan.  I.e., don't send follow-up scan requests
+            // to each advertiser.
+            active: false,
+            // https://esp32.com/viewtopic.php?f=13&t=15985 (ble + wifi)
+            interval_ms: None,
+            window_ms: None,
+            duration_ms: i32::MAX,
+            // Tell the controller to filter duplicates; we don't want to
+            // process repeated advertisements from the same device.
+            filter_duplicates: true,
+            filter: Default::default(),
+        }
+    }
 
-        // https://esp32.com/viewtopic.php?f=13&t=15985 (ble + wifi)
-        self.disc_params.itvl = esp_idf_sys::BLE_GAP_SCAN_SLOW_INTERVAL1 as u16;
-        self.disc_params.window = esp_idf_sys::BLE_GAP_SCAN_SLOW_WINDOW1 as u16;
-        self.disc_params.filter_policy = esp_idf_sys::BLE_HCI_SCAN_FILT_NO_WL as u8;
-        self.disc_params.set_limited(0);
+    pub fn start(&mut self) -> Result<&Receiver<BlePeerDevice>, BleError> {
+        self.start_with(self.scan_tx.clone())?;
+        Ok(&self.scan_rx)
     }
 
-    pub fn start(&mut self) -> Result<&Receiver<BlePeerDevice>> {
-        // Figure out address to use while advertising (no privacy for now)
-        let mut own_addr_type = 0_u8;
-        let rc = unsafe { esp_idf_sys::ble_hs_id_infer_auto(0, &mut own_addr_type) };
-        if rc != 0 {
-            anyhow::bail!("Error determining address type; rc={}", rc);
-        }
+    // Same as start, but the devices can be awaited. The channel is closed
+    // once the scan finishes.
+    pub fn start_async(&mut self) -> Result<BleReceiver<BlePeerDevice>, BleError> {
+        let (tx, rx) = super::channel::channel();
+        self.start_with(tx)?;
+        Ok(rx)
+    }
 
-        // Callback.
-        let ble = self.ble.lock().weak_ref();
-        let scan_tx = self.scan_tx.clone();
-        self.callback = Box::new(move |event: BlePeerDeviceDiscoveryEvent| match event {
-            BlePeerDeviceDiscoveryEvent::Discovery(name, address) => match ble.upgrade() {
-                Some(ble) => {
-                    let dev = BlePeerDevice::new(address, Arc::downgrade(&ble));
-                    let dev_state = BlePeerDeviceSharedState::new(name);
-                    let addr = dev.address().clone();
-                    ble.lock().devices.insert(addr, dev_state);
-                    scan_tx.send(dev).ok();
-                }
-                None => panic!("Cannot upgrade weak reference to BLE during scan"),
-            },
-            BlePeerDeviceDiscoveryEvent::DiscoveryFinished => {}
-        });
-        let cb_arg: *mut _ = &mut self.callback;
+    fn start_with(
+        &mut self,
+        scan_tx: impl BleEventSender<BlePeerDevice> + Send + 'static,
+    ) -> Result<(), BleError> {
+        let (own_addr_type, ble) = {
+            let mut ble = self.ble.lock();
+            (ble.own_addr_type()?, ble.weak_ref())
+        };
 
-        // Start the scanning thread.
-        let rc = unsafe {
+        // Held until the session is in place, so the first events wait for
+        // it. NimBLE never calls back from ble_gap_disc itself.
+        let mut sessions = SESSIONS.lock();
+        let id = sessions.next_id;
+        // 0 would be a null cb_arg.
+        sessions.next_id = sessions.next_id.checked_add(1).unwrap_or(1);
+        BleError::check(unsafe {
             esp_idf_sys::ble_gap_disc(
                 own_addr_type,
-                i32::MAX,
+                self.duration_ms,
                 &self.disc_params,
                 Some(BleScan::ble_on_gap_scan_event),
-                cb_arg as *mut esp_idf_sys::c_types::c_void,
+                id as *mut esp_idf_sys::c_types::c_void,
             )
-        };
-        if rc != 0 {
-            anyhow::bail!("Error initiating GAP discovery procedure; rc={}", rc);
-        }
-
-        Ok(&self.scan_rx)
+        })?;
+        sessions.current = Some(BleScanSession {
+            id,
+            ble,
+            filter: self.filter.clone(),
+            scan_tx: Box::new(scan_tx),
+        });
+        self.session_id = Some(id);
+        Ok(())
     }
 
-    pub fn stop(&mut self) -> Result<()> {
-        if unsafe { esp_idf_sys::ble_gap_disc_cancel() } == 0 {
-            Ok(())
-        } else {
-            anyhow::bail!("Failure stopping discovery");
+    // Does nothing if the scan already completed.
+    pub fn stop(&mut self) -> Result<(), BleError> {
+        let mut sessions = SESSIONS.lock();
+        match (&sessions.current, self.session_id.take()) {
+            (Some(session), Some(id)) if session.id == id => {
+                let rc = unsafe { esp_idf_sys::ble_gap_disc_cancel() };
+                // Ended either way, the cancel only fails if the scan just
+                // completed and its DISC_COMPLETE is waiting for the lock.
+                sessions.current = None;
+                match BleError::check(rc) {
+                    Err(BleError::Already) => Ok(()),
+                    result => result,
+                }
+            }
+            _ => Ok(()),
         }
     }
 
-    pub fn flush_duplicates(&self) -> Result<()> {
+    pub fn flush_duplicates(&self) -> Result<(), BleError> {
         unsafe { esp_idf_sys::esp!(esp_idf_sys::esp_ble_scan_dupilcate_list_flush())? }
         Ok(())
     }
@@ -114,9 +341,7 @@ impl BleScan {
         cb_arg: *mut esp_idf_sys::c_types::c_void,
     ) -> esp_idf_sys::c_types::c_int {
         let event = *event;
-        let cb_arg = (cb_arg as *mut Box<dyn FnMut(BlePeerDeviceDiscoveryEvent)>)
-            .as_mut()
-            .unwrap();
+        let id = cb_arg as usize;
 
         match event.type_ as u32 {
             esp_idf_sys::BLE_GAP_EVENT_DISC => {
@@ -132,31 +357,36 @@ impl BleScan {
                     log::error!("BLE parsing fields failed");
                     return 0;
                 }
-                let name = if fields.name_is_complete() == 1 {
-                    let name = std::str::from_utf8(std::slice::from_raw_parts(
-                        fields.name,
-                        fields.name_len as usize,
-                    ))
-                    .unwrap();
-                    name
-                } else {
-                    ""
+                let report = BleAdvertisementReport {
+                    event_type: event.__bindgen_anon_1.disc.event_type.into(),
+                    rssi: event.__bindgen_anon_1.disc.rssi,
+                    data: BleAdvertisementData::from_native(&fields),
+                };
+                let sessions = SESSIONS.lock();
+                match &sessions.current {
+                    Some(session) if session.id == id => session.report(
+                        BlePeerDeviceAddress(event.__bindgen_anon_1.disc.addr),
+                        report,
+                    ),
+                    // Stopped while the event was on its way.
+                    _ => {}
                 }
-                .to_owned();
-                cb_arg(BlePeerDeviceDiscoveryEvent::Discovery(
-                    name,
-                    BlePeerDeviceAddress(event.__bindgen_anon_1.disc.addr),
-                ));
                 0
             }
 
             esp_idf_sys::BLE_GAP_EVENT_DISC_COMPLETE => {
                 log::info!("BLE gap event, BLE_GAP_EVENT_DISC_COMPLETE");
-                cb_arg(BlePeerDeviceDiscoveryEvent::DiscoveryFinished);
+                let mut sessions = SESSIONS.lock();
+                if matches!(&sessions.current, Some(session) if session.id == id) {
+                    sessions.current = None;
+                }
                 0
             }
 
-            _ => panic!("Unexpected event at ble_on_gap_scan_event"),
+            event_type => {
+                log::warn!("BLE scan: unexpected gap event {}", event_type);
+                0
+            }
         }
     }
 }
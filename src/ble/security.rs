@@ -0,0 +1,183 @@
+// Security manager settings, shared by every connection (central and
+// peripheral role).
+// https://github.com/espressif/esp-idf/blob/master/examples/bluetooth/nimble/bleprph/main/main.c
+
+use super::dev::{BleConnHandle, BlePeerDeviceAddress};
+use esp_idf_hal::mutex::Mutex;
+
+static PASSKEY_HANDLER: Mutex<Option<BlePasskeyHandler>> = Mutex::new(None);
+
+// What we can use to authenticate the peer during pairing. Anything other than
+// NoInputNoOutput needs a passkey handler, see Ble::set_passkey_handler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BleIoCapabilities {
+    DisplayOnly,
+    DisplayYesNo,
+    KeyboardOnly,
+    NoInputNoOutput,
+    KeyboardDisplay,
+}
+
+impl BleIoCapabilities {
+    fn native(&self) -> u8 {
+        (match self {
+            Self::DisplayOnly => esp_idf_sys::BLE_HS_IO_DISPLAY_ONLY,
+            Self::DisplayYesNo => esp_idf_sys::BLE_HS_IO_DISPLAY_YESNO,
+            Self::KeyboardOnly => esp_idf_sys::BLE_HS_IO_KEYBOARD_ONLY,
+            Self::NoInputNoOutput => esp_idf_sys::BLE_HS_IO_NO_INPUT_OUTPUT,
+            Self::KeyboardDisplay => esp_idf_sys::BLE_HS_IO_KEYBOARD_DISPLAY,
+        }) as u8
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BleSecurity {
+    // Store the keys so the next connection doesn't need to pair again.
+    pub bonding: bool,
+    pub io_capabilities: BleIoCapabilities,
+    // Use LE Secure Connections pairing. NimBLE still falls back to legacy
+    // pairing for peers that don't support it.
+    pub secure_connections: bool,
+    // Require an authenticated (MITM protected) link, connections that end
+    // up unauthenticated are dropped. Needs io_capabilities on both sides
+    // that allow it.
+    pub mitm: bool,
+}
+
+impl BleSecurity {
+    pub(super) fn apply(&self) {
+        // Identity keys are exchanged too so bonded peers using resolvable
+        // private addresses can be recognized, NimBLE loads the stored IRKs
+        // into the resolving list on sync.
+        let key_dist =
+            (esp_idf_sys::BLE_SM_PAIR_KEY_DIST_ENC | esp_idf_sys::BLE_SM_PAIR_KEY_DIST_ID) as u8;
+        unsafe {
+            esp_idf_sys::ble_hs_cfg.sm_io_cap = self.io_capabilities.native();
+            esp_idf_sys::ble_hs_cfg.set_sm_bonding(self.bonding as u32);
+            esp_idf_sys::ble_hs_cfg.set_sm_sc(self.secure_connections as u32);
+            esp_idf_sys::ble_hs_cfg.set_sm_mitm(self.mitm as u32);
+            esp_idf_sys::ble_hs_cfg.sm_our_key_dist = if self.bonding { key_dist } else { 0 };
+            esp_idf_sys::ble_hs_cfg.sm_their_key_dist = if self.bonding { key_dist } else { 0 };
+        }
+    }
+}
+
+// Just Works pairing with bonding, what the steam controller expects.
+impl Default for BleSecurity {
+    fn default() -> Self {
+        Self {
+            bonding: true,
+            io_capabilities: BleIoCapabilities::NoInputNoOutput,
+            secure_connections: true,
+            mitm: false,
+        }
+    }
+}
+
+pub enum BlePasskeyAction {
+    // Show the passkey to the user, who types it on the peer.
+    Display(u32),
+    // The user types the passkey shown by the peer.
+    Input,
+    // Both sides show the same number, the user confirms it matches.
+    NumericComparison(u32),
+}
+
+pub enum BlePasskeyReply {
+    // Answer to BlePasskeyAction::Input.
+    Passkey(u32),
+    Accept,
+    // Aborts the pairing.
+    Reject,
+}
+
+// Called from the NimBLE host task, the pairing waits until it returns.
+pub type BlePasskeyHandler =
+    Box<dyn FnMut(BleConnHandle, BlePasskeyAction) -> BlePasskeyReply + Send>;
+
+pub(super) fn set_passkey_handler(handler: Option<BlePasskeyHandler>) {
+    *PASSKEY_HANDLER.lock() = handler;
+}
+
+// Handles BLE_GAP_EVENT_REPEAT_PAIRING: a bonded peer wants to pair again,
+// usually because it lost its keys. The old bond is deleted and the pairing
+// goes ahead.
+pub(super) unsafe fn on_repeat_pairing(conn_handle: u16) -> esp_idf_sys::c_types::c_int {
+    let mut desc: esp_idf_sys::ble_gap_conn_desc = Default::default();
+    let rc = esp_idf_sys::ble_gap_conn_find(conn_handle, &mut desc);
+    if rc != 0 {
+        log::error!(
+            "BLE security: repeat pairing on unknown conn_handle={}",
+            conn_handle
+        );
+        return esp_idf_sys::BLE_GAP_REPEAT_PAIRING_IGNORE as esp_idf_sys::c_types::c_int;
+    }
+    log::warn!(
+        "BLE security: {} paired again, deleting the old bond",
+        BlePeerDeviceAddress(desc.peer_id_addr)
+    );
+    esp_idf_sys::ble_store_util_delete_peer(&desc.peer_id_addr);
+    esp_idf_sys::BLE_GAP_REPEAT_PAIRING_RETRY as esp_idf_sys::c_types::c_int
+}
+
+// Handles BLE_GAP_EVENT_PASSKEY_ACTION. Without a handler passkeys to display
+// are only logged and everything else is rejected.
+pub(super) unsafe fn on_passkey_action(
+    conn_handle: u16,
+    params: &esp_idf_sys::ble_gap_passkey_params,
+) {
+    let mut io = esp_idf_sys::ble_sm_io {
+        action: params.action,
+        ..Default::default()
+    };
+    let action = match params.action as u32 {
+        esp_idf_sys::BLE_SM_IOACT_DISP => {
+            // Six decimal digits.
+            io.__bindgen_anon_1.passkey = esp_idf_sys::esp_random() % 1_000_000;
+            BlePasskeyAction::Display(io.__bindgen_anon_1.passkey)
+        }
+        esp_idf_sys::BLE_SM_IOACT_INPUT => BlePasskeyAction::Input,
+        esp_idf_sys::BLE_SM_IOACT_NUMCMP => BlePasskeyAction::NumericComparison(params.numcmp),
+        action => {
+            log::warn!("BLE security: unsupported passkey action {}", action);
+            return;
+        }
+    };
+
+    let reply = match PASSKEY_HANDLER.lock().as_mut() {
+        Some(handler) => handler(conn_handle as BleConnHandle, action),
+        None => match action {
+            BlePasskeyAction::Display(passkey) => {
+                log::info!(
+                    "BLE security: passkey for conn_handle={} is {:06}",
+                    conn_handle,
+                    passkey
+                );
+                BlePasskeyReply::Accept
+            }
+            _ => {
+                log::error!("BLE security: passkey requested but there's no handler");
+                BlePasskeyReply::Reject
+            }
+        },
+    };
+
+    match (params.action as u32, reply) {
+        (esp_idf_sys::BLE_SM_IOACT_NUMCMP, reply) => {
+            io.__bindgen_anon_1.numcmp_accept = matches!(reply, BlePasskeyReply::Accept) as u8;
+        }
+        (esp_idf_sys::BLE_SM_IOACT_INPUT, BlePasskeyReply::Passkey(passkey)) => {
+            io.__bindgen_anon_1.passkey = passkey;
+        }
+        (esp_idf_sys::BLE_SM_IOACT_DISP, BlePasskeyReply::Accept) => {}
+        _ => {
+            esp_idf_sys::ble_gap_terminate(conn_handle, esp_idf_sys::BLE_ERR_AUTH_FAIL as u8);
+            return;
+        }
+    }
+
+    let rc = esp_idf_sys::ble_sm_inject_io(conn_handle, &mut io);
+    if rc != 0 {
+        log::error!("BLE security: error injecting passkey; rc={}", rc);
+    }
+}
@@ -0,0 +1,270 @@
+// GATT server (peripheral role). Services are defined with the builders below
+// and registered with NimBLE before the host syncs, see Ble::new_with_server.
+// https://github.com/espressif/esp-idf/blob/master/examples/bluetooth/nimble/bleprph/main/gatt_svr.c
+
+use super::{
+    dev::BleConnHandle,
+    error::{BleAttError, BleError},
+    uuid::BleUUID,
+};
+use esp_idf_hal::mutex::Mutex;
+use std::sync::{
+    atomic::{AtomicU16, Ordering},
+    Arc,
+};
+
+pub type BleGattReadHandler = Box<dyn FnMut(BleConnHandle) -> Result<Vec<u8>, BleAttError> + Send>;
+pub type BleGattWriteHandler =
+    Box<dyn FnMut(BleConnHandle, &[u8]) -> Result<(), BleAttError> + Send>;
+
+// Handlers are called without the state locked, so they can use value and
+// set_value.
+struct BleGattCharacteristicState {
+    value: Vec<u8>,
+    on_read: Option<Arc<Mutex<BleGattReadHandler>>>,
+    on_write: Option<Arc<Mutex<BleGattWriteHandler>>>,
+}
+
+// A characteristic exposed by our GATT server. Clones share the same value
+// and handlers, so keep a clone around to update the value (and notify
+// subscribers) once the server is running.
+#[derive(Clone)]
+pub struct BleGattCharacteristic {
+    uuid: BleUUID,
+    flags: u16,
+    // Filled in by NimBLE when the service is registered.
+    val_handle: Arc<AtomicU16>,
+    state: Arc<Mutex<BleGattCharacteristicState>>,
+}
+
+impl BleGattCharacteristic {
+    pub fn new(uuid: BleUUID) -> Self {
+        Self {
+            uuid,
+            flags: 0,
+            val_handle: Arc::new(AtomicU16::new(0)),
+            state: Arc::new(Mutex::new(BleGattCharacteristicState {
+                value: vec![],
+                on_read: None,
+                on_write: None,
+            })),
+        }
+    }
+
+    pub fn uuid(&self) -> &BleUUID {
+        &self.uuid
+    }
+
+    // Reads return the current value unless a read handler is set.
+    pub fn read(mut self) -> Self {
+        self.flags |= esp_idf_sys::BLE_GATT_CHR_F_READ as u16;
+        self
+    }
+
+    pub fn write(mut self) -> Self {
+        self.flags |= esp_idf_sys::BLE_GATT_CHR_F_WRITE as u16;
+        self
+    }
+
+    pub fn write_no_response(mut self) -> Self {
+        self.flags |= esp_idf_sys::BLE_GATT_CHR_F_WRITE_NO_RSP as u16;
+        self
+    }
+
+    pub fn notify(mut self) -> Self {
+        self.flags |= esp_idf_sys::BLE_GATT_CHR_F_NOTIFY as u16;
+        self
+    }
+
+    pub fn indicate(mut self) -> Self {
+        self.flags |= esp_idf_sys::BLE_GATT_CHR_F_INDICATE as u16;
+        self
+    }
+
+    pub fn on_read(
+        self,
+        handler: impl FnMut(BleConnHandle) -> Result<Vec<u8>, BleAttError> + Send + 'static,
+    ) -> Self {
+        let handler: BleGattReadHandler = Box::new(handler);
+        self.state.lock().on_read = Some(Arc::new(Mutex::new(handler)));
+        self.read()
+    }
+
+    // The handler is called before the written value is stored, returning an
+    // error rejects the write.
+    pub fn on_write(
+        self,
+        handler: impl FnMut(BleConnHandle, &[u8]) -> Result<(), BleAttError> + Send + 'static,
+    ) -> Self {
+        let handler: BleGattWriteHandler = Box::new(handler);
+        self.state.lock().on_write = Some(Arc::new(Mutex::new(handler)));
+        self.write()
+    }
+
+    pub fn value(&self) -> Vec<u8> {
+        self.state.lock().value.clone()
+    }
+
+    // Stores the value and, once registered, notifies / indicates it to every
+    // subscribed client.
+    pub fn set_value(&self, value: &[u8]) {
+        self.state.lock().value = value.to_vec();
+
+        // The lock must be released at this point, NimBLE reads the value
+        // back through ble_on_gatt_access while sending the notifications.
+        if let Some(val_handle) = self.val_handle() {
+            unsafe { esp_idf_sys::ble_gatts_chr_updated(val_handle) };
+        }
+    }
+
+    pub fn val_handle(&self) -> Option<u16> {
+        match self.val_handle.load(Ordering::Relaxed) {
+            0 => None,
+            handle => Some(handle),
+        }
+    }
+
+    unsafe extern "C" fn ble_on_gatt_access(
+        conn_handle: u16,
+        _attr_handle: u16,
+        ctxt: *mut esp_idf_sys::ble_gatt_access_ctxt,
+        arg: *mut esp_idf_sys::c_types::c_void,
+    ) -> esp_idf_sys::c_types::c_int {
+        let state = match (arg as *const Mutex<BleGattCharacteristicState>).as_ref() {
+            Some(state) => state,
+            None => {
+                log::error!("BLE gatt server: access without characteristic state");
+                return esp_idf_sys::BLE_ATT_ERR_UNLIKELY as esp_idf_sys::c_types::c_int;
+            }
+        };
+        let ctxt = &mut *ctxt;
+
+        match ctxt.op as u32 {
+            esp_idf_sys::BLE_GATT_ACCESS_OP_READ_CHR => {
+                let on_read = state.lock().on_read.clone();
+                let value = match on_read {
+                    Some(handler) => match (*handler.lock())(conn_handle as BleConnHandle) {
+                        Ok(value) => value,
+                        Err(e) => return e.code() as esp_idf_sys::c_types::c_int,
+                    },
+                    None => state.lock().value.clone(),
+                };
+                let rc = esp_idf_sys::os_mbuf_append(
+                    ctxt.om,
+                    value.as_ptr() as *const esp_idf_sys::c_types::c_void,
+                    value.len() as u16,
+                );
+                if rc == 0 {
+                    0
+                } else {
+                    esp_idf_sys::BLE_ATT_ERR_INSUFFICIENT_RES as esp_idf_sys::c_types::c_int
+                }
+            }
+
+            esp_idf_sys::BLE_GATT_ACCESS_OP_WRITE_CHR => {
+                let data = super::os_mbuf_to_vec(ctxt.om);
+                let on_write = state.lock().on_write.clone();
+                if let Some(handler) = on_write {
+                    if let Err(e) = (*handler.lock())(conn_handle as BleConnHandle, &data) {
+                        return e.code() as esp_idf_sys::c_types::c_int;
+                    }
+                }
+                state.lock().value = data;
+                0
+            }
+
+            op => {
+                log::error!("BLE gatt server: unexpected access op {}", op);
+                esp_idf_sys::BLE_ATT_ERR_UNLIKELY as esp_idf_sys::c_types::c_int
+            }
+        }
+    }
+}
+
+pub struct BleGattService {
+    uuid: BleUUID,
+    characteristics: Vec<BleGattCharacteristic>,
+    // NimBLE definitions, they must outlive the host.
+    chr_defs: Vec<esp_idf_sys::ble_gatt_chr_def>,
+}
+
+impl BleGattService {
+    pub fn new(uuid: BleUUID) -> Self {
+        Self {
+            uuid,
+            characteristics: vec![],
+            chr_defs: vec![],
+        }
+    }
+
+    pub fn uuid(&self) -> &BleUUID {
+        &self.uuid
+    }
+
+    pub fn characteristic(mut self, chr: BleGattCharacteristic) -> Self {
+        self.characteristics.push(chr);
+        self
+    }
+}
+
+#[derive(Default)]
+pub struct BleGattServer {
+    services: Vec<BleGattService>,
+    // NimBLE definitions, they must outlive the host.
+    svc_defs: Vec<esp_idf_sys::ble_gatt_svc_def>,
+}
+
+impl BleGattServer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn service(mut self, svc: BleGattService) -> Self {
+        self.services.push(svc);
+        self
+    }
+
+    // Registers the services with NimBLE. Must be called after nimble_port_init
+    // and before the host task is started, which is what Ble::init does. The
+    // server must not be moved out of Ble after this.
+    pub(super) fn register(&mut self) -> Result<(), BleError> {
+        for svc in &mut self.services {
+            svc.chr_defs = svc
+                .characteristics
+                .iter()
+                .map(|chr| esp_idf_sys::ble_gatt_chr_def {
+                    uuid: chr.uuid.native() as *const _ as *const esp_idf_sys::ble_uuid_t,
+                    access_cb: Some(BleGattCharacteristic::ble_on_gatt_access),
+                    arg: Arc::as_ptr(&chr.state) as *mut esp_idf_sys::c_types::c_void,
+                    flags: chr.flags,
+                    val_handle: Arc::as_ptr(&chr.val_handle) as *mut u16,
+                    ..Default::default()
+                })
+                .collect();
+            // Zeroed entry terminates the array.
+            svc.chr_defs.push(Default::default());
+        }
+
+        self.svc_defs = self
+            .services
+            .iter()
+            .map(|svc| esp_idf_sys::ble_gatt_svc_def {
+                type_: esp_idf_sys::BLE_GATT_SVC_TYPE_PRIMARY as u8,
+                uuid: svc.uuid.native() as *const _ as *const esp_idf_sys::ble_uuid_t,
+                characteristics: svc.chr_defs.as_ptr(),
+                ..Default::default()
+            })
+            .collect();
+        self.svc_defs.push(Default::default());
+
+        unsafe {
+            super::ble_svc_gap_init();
+            super::ble_svc_gatt_init();
+
+            BleError::check(esp_idf_sys::ble_gatts_count_cfg(self.svc_defs.as_ptr()))?;
+            BleError::check(esp_idf_sys::ble_gatts_add_svcs(self.svc_defs.as_ptr()))?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,16 +1,21 @@
-use super::{chr::BlePeerCharacteristic, dev::BleConnHandle, uuid::BleUUID};
-use anyhow::Result;
+use super::channel::BleEventSender;
+use super::{chr::BlePeerCharacteristic, dev::BleConnHandle, error::BleError, uuid::BleUUID, Ble};
+use esp_idf_hal::mutex::Mutex;
+use std::sync::Weak;
+use std::time::Instant;
 
 enum BlePeerCharacteristicDiscoveryEvent {
-    Discovery(BlePeerCharacteristic),
-    DiscoveryFinished,
+    Discovery(u16, esp_idf_sys::ble_gatt_chr),
+    DiscoveryFinished(u16),
 }
 
+#[derive(Clone)]
 pub struct BlePeerService {
     pub(super) conn_handle: u16,
     pub(super) start_handle: u16,
     pub(super) end_handle: u16,
     pub(super) uuid: BleUUID,
+    pub(super) ble: Weak<Mutex<Ble>>,
 }
 
 impl BlePeerService {
@@ -18,51 +23,147 @@ impl BlePeerService {
         &self.uuid
     }
 
-    pub fn get_characteristics(&self) -> Result<Vec<BlePeerCharacteristic>> {
-        log::info!("Retrieving characteristics for service {}", self);
+    pub fn get_characteristics(&self) -> Result<Vec<BlePeerCharacteristic>, BleError> {
+        let deadline = Instant::now() + Ble::timeouts().discovery;
+
+        // Start the discovery, results are sent back through a channel.
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.start_characteristic_discovery(None, tx)?;
 
+        // Wait for results.
         let mut characteristics = vec![];
-        {
-            // Callback.
-            let (tx, rx) = std::sync::mpsc::channel();
-            let characteristics = &mut characteristics;
-            let mut callback: Box<dyn FnMut(BlePeerCharacteristicDiscoveryEvent)> =
-                Box::new(move |event| match event {
-                    BlePeerCharacteristicDiscoveryEvent::Discovery(chr) => {
-                        characteristics.push(chr);
-                    }
-                    BlePeerCharacteristicDiscoveryEvent::DiscoveryFinished => tx.send(()).unwrap(),
-                });
+        loop {
+            let event = super::procedure_recv(
+                &rx,
+                deadline,
+                self.conn_handle as BleConnHandle,
+                "characteristic discovery",
+            )?;
+            if self.on_characteristic_discovery_event(&mut characteristics, event)? {
+                return Ok(characteristics);
+            }
+        }
+    }
+
+    pub async fn get_characteristics_async(&self) -> Result<Vec<BlePeerCharacteristic>, BleError> {
+        let (tx, mut rx) = super::channel::channel();
+        self.start_characteristic_discovery(None, tx)?;
+
+        let mut characteristics = vec![];
+        loop {
+            let event = super::procedure_next(&mut rx, "characteristic discovery").await?;
+            if self.on_characteristic_discovery_event(&mut characteristics, event)? {
+                return Ok(characteristics);
+            }
+        }
+    }
+
+    // Characteristics with this UUID, usually just one. Saves reporting every
+    // characteristic of the service back to us.
+    pub fn get_characteristics_by_uuid(
+        &self,
+        uuid: &BleUUID,
+    ) -> Result<Vec<BlePeerCharacteristic>, BleError> {
+        let deadline = Instant::now() + Ble::timeouts().discovery;
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.start_characteristic_discovery(Some(uuid), tx)?;
+
+        let mut characteristics = vec![];
+        loop {
+            let event = super::procedure_recv(
+                &rx,
+                deadline,
+                self.conn_handle as BleConnHandle,
+                "characteristic discovery",
+            )?;
+            if self.on_characteristic_discovery_event(&mut characteristics, event)? {
+                return Ok(characteristics);
+            }
+        }
+    }
+
+    pub async fn get_characteristics_by_uuid_async(
+        &self,
+        uuid: &BleUUID,
+    ) -> Result<Vec<BlePeerCharacteristic>, BleError> {
+        let (tx, mut rx) = super::channel::channel();
+        self.start_characteristic_discovery(Some(uuid), tx)?;
+
+        let mut characteristics = vec![];
+        loop {
+            let event = super::procedure_next(&mut rx, "characteristic discovery").await?;
+            if self.on_characteristic_discovery_event(&mut characteristics, event)? {
+                return Ok(characteristics);
+            }
+        }
+    }
 
-            // Start the discovery thread.
-            let cb_arg: *mut _ = &mut callback;
-            let rc = unsafe {
-                esp_idf_sys::ble_gattc_disc_all_chrs(
+    fn start_characteristic_discovery(
+        &self,
+        uuid: Option<&BleUUID>,
+        tx: impl BleEventSender<BlePeerCharacteristicDiscoveryEvent> + 'static,
+    ) -> Result<(), BleError> {
+        log::info!("Retrieving characteristics for service {}", self);
+
+        let cb_arg = super::procedure_cb_arg(tx);
+        let rc = unsafe {
+            match uuid {
+                Some(uuid) => esp_idf_sys::ble_gattc_disc_chrs_by_uuid(
+                    self.conn_handle,
+                    self.start_handle,
+                    self.end_handle,
+                    uuid.native() as *const _ as *const esp_idf_sys::ble_uuid_t,
+                    Some(BlePeerService::ble_on_gatt_disc_chrs),
+                    cb_arg,
+                ),
+                None => esp_idf_sys::ble_gattc_disc_all_chrs(
                     self.conn_handle,
                     self.start_handle,
                     self.end_handle,
                     Some(BlePeerService::ble_on_gatt_disc_chrs),
-                    cb_arg as *mut esp_idf_sys::c_types::c_void,
-                )
-            };
-            if rc != 0 {
-                return Err(anyhow::anyhow!(
-                    "Error initiating GAP characteristic discovery procedure; rc={}",
-                    rc
-                ));
+                    cb_arg,
+                ),
             }
+        };
+        if let Err(e) = BleError::check(rc) {
+            unsafe { super::procedure_release::<BlePeerCharacteristicDiscoveryEvent>(cb_arg) };
+            return Err(e);
+        }
+        Ok(())
+    }
 
-            // Wait for results.
-            loop {
-                match rx.recv() {
-                    Ok(_) => break,
-                    Err(e) => anyhow::bail!("Error retrieving device characteristics: {}", e),
-                }
+    // Returns true once the discovery is complete.
+    fn on_characteristic_discovery_event(
+        &self,
+        characteristics: &mut Vec<BlePeerCharacteristic>,
+        event: BlePeerCharacteristicDiscoveryEvent,
+    ) -> Result<bool, BleError> {
+        match event {
+            BlePeerCharacteristicDiscoveryEvent::Discovery(conn_handle, chr) => {
+                characteristics.push(BlePeerCharacteristic {
+                    conn_handle: conn_handle as BleConnHandle,
+                    def_handle: chr.def_handle,
+                    val_handle: chr.val_handle,
+                    end_handle: 0,
+                    properties: chr.properties,
+                    uuid: BleUUID::from(chr.uuid),
+                    ble: self.ble.clone(),
+                });
+                return Ok(false);
+            }
+            BlePeerCharacteristicDiscoveryEvent::DiscoveryFinished(status)
+                if status == esp_idf_sys::BLE_HS_EDONE as u16 => {}
+            BlePeerCharacteristicDiscoveryEvent::DiscoveryFinished(status) => {
+                return Err(BleError::from_code(status as u32)
+                    .unwrap_or(BleError::Aborted("characteristic discovery")))
             }
         }
 
         // Configuramos el end handle de las caracteristicas con el def_handle
-        // de la proxima characteristica - 1.
+        // de la proxima characteristica - 1. When searching by UUID the next
+        // one found isn't necessarily the next one in the service, the
+        // descriptor discovery takes care of that.
         let mut iter = characteristics.iter_mut().peekable();
         while let Some(chr) = iter.next() {
             if let Some(next) = iter.peek() {
@@ -72,8 +173,7 @@ impl BlePeerService {
             }
             log::info!("Found: {}", chr);
         }
-
-        Ok(characteristics)
+        Ok(true)
     }
 
     unsafe extern "C" fn ble_on_gatt_disc_chrs(
@@ -82,24 +182,18 @@ impl BlePeerService {
         chr: *const esp_idf_sys::ble_gatt_chr,
         cb_arg: *mut esp_idf_sys::c_types::c_void,
     ) -> esp_idf_sys::c_types::c_int {
-        let cb_arg = (cb_arg as *mut Box<dyn FnMut(BlePeerCharacteristicDiscoveryEvent)>)
-            .as_mut()
-            .unwrap();
-        if !chr.is_null() {
-            let chr = *chr;
-            cb_arg(BlePeerCharacteristicDiscoveryEvent::Discovery(
-                BlePeerCharacteristic {
-                    conn_handle: conn_handle as BleConnHandle,
-                    def_handle: chr.def_handle,
-                    val_handle: chr.val_handle,
-                    end_handle: 0,
-                    properties: chr.properties,
-                    uuid: BleUUID::from(chr.uuid),
-                },
-            ));
-        }
-        if (if error.is_null() { 0 } else { (*error).status }) == esp_idf_sys::BLE_HS_EDONE as u16 {
-            cb_arg(BlePeerCharacteristicDiscoveryEvent::DiscoveryFinished);
+        let status = if error.is_null() { 0 } else { (*error).status };
+        if status == 0 && !chr.is_null() {
+            super::procedure_send(
+                cb_arg,
+                BlePeerCharacteristicDiscoveryEvent::Discovery(conn_handle, *chr),
+            );
+        } else {
+            super::procedure_send(
+                cb_arg,
+                BlePeerCharacteristicDiscoveryEvent::DiscoveryFinished(status),
+            );
+            super::procedure_release::<BlePeerCharacteristicDiscoveryEvent>(cb_arg);
         }
         0
     }
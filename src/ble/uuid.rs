@@ -1,6 +1,7 @@
-use anyhow::Result;
+use super::error::BleError;
 use esp_idf_sys::{
-    ble_uuid128_t, ble_uuid16_t, ble_uuid_any_t, ble_uuid_t, BLE_UUID_TYPE_128, BLE_UUID_TYPE_16,
+    ble_uuid128_t, ble_uuid16_t, ble_uuid32_t, ble_uuid_any_t, ble_uuid_t, BLE_UUID_TYPE_128,
+    BLE_UUID_TYPE_16, BLE_UUID_TYPE_32,
 };
 
 #[derive(Clone, Copy)]
@@ -9,49 +10,81 @@ pub struct BleUUID {
 }
 
 impl BleUUID {
-    pub fn parse(value: &str) -> Result<BleUUID> {
-        let value = value.replace("-", "");
-        let ble_uuid = match value.len() {
-            4 => ble_uuid_any_t {
+    pub const fn from_u16(value: u16) -> Self {
+        Self {
+            ble_uuid: ble_uuid_any_t {
                 u16_: ble_uuid16_t {
                     u: ble_uuid_t {
                         type_: BLE_UUID_TYPE_16 as u8,
                     },
-                    value: {
-                        let value = &value
-                            .as_bytes()
-                            .chunks(2)
-                            .map(|i| u8::from_str_radix(std::str::from_utf8(i).unwrap(), 16))
-                            .rev()
-                            .collect::<Result<Vec<u8>, _>>()?[0..2];
-                        ((value[0] as u16) << 8) | value[1] as u16
+                    value,
+                },
+            },
+        }
+    }
+
+    pub const fn from_u32(value: u32) -> Self {
+        Self {
+            ble_uuid: ble_uuid_any_t {
+                u32_: ble_uuid32_t {
+                    u: ble_uuid_t {
+                        type_: BLE_UUID_TYPE_32 as u8,
                     },
+                    value,
                 },
             },
-            32 => ble_uuid_any_t {
+        }
+    }
+
+    // E.g. 0x100f6c32_1735_4313_b402_38567131e5f3. NimBLE keeps the bytes
+    // least significant first.
+    pub const fn from_u128(value: u128) -> Self {
+        Self {
+            ble_uuid: ble_uuid_any_t {
                 u128_: ble_uuid128_t {
                     u: ble_uuid_t {
                         type_: BLE_UUID_TYPE_128 as u8,
                     },
-                    value: value
-                        .as_bytes()
-                        .chunks(2)
-                        .map(|i| u8::from_str_radix(std::str::from_utf8(i).unwrap(), 16))
-                        .rev()
-                        .collect::<Result<Vec<u8>, _>>()?[0..16]
-                        .try_into()?,
+                    value: value.to_le_bytes(),
                 },
             },
-            _ => {
-                anyhow::bail!("Unrecognized UUID string length");
-            }
-        };
-        Ok(Self { ble_uuid })
+        }
+    }
+
+    // Hex digits as written in the specs, most significant first: "2902",
+    // "0000fe2c" or "100f6c32-1735-4313-b402-38567131e5f3".
+    pub fn parse(value: &str) -> Result<BleUUID, BleError> {
+        let invalid = || BleError::Invalid(format!("UUID {:?}", value));
+        let hex = value.replace("-", "");
+        if !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        Ok(match hex.len() {
+            4 => Self::from_u16(u16::from_str_radix(&hex, 16).map_err(|_| invalid())?),
+            8 => Self::from_u32(u32::from_str_radix(&hex, 16).map_err(|_| invalid())?),
+            32 => Self::from_u128(u128::from_str_radix(&hex, 16).map_err(|_| invalid())?),
+            _ => return Err(invalid()),
+        })
     }
 
     pub fn native(&self) -> &ble_uuid_any_t {
         &self.ble_uuid
     }
+
+    // Type and value, shorter UUIDs zero extended.
+    fn value(&self) -> (u8, u128) {
+        unsafe {
+            let type_ = self.ble_uuid.u.type_;
+            let value = match type_ as u32 {
+                BLE_UUID_TYPE_16 => self.ble_uuid.u16_.value as u128,
+                BLE_UUID_TYPE_32 => self.ble_uuid.u32_.value as u128,
+                BLE_UUID_TYPE_128 => u128::from_le_bytes(self.ble_uuid.u128_.value),
+                // Never built by NimBLE.
+                _ => 0,
+            };
+            (type_, value)
+        }
+    }
 }
 
 impl From<ble_uuid_any_t> for BleUUID {
@@ -60,68 +93,51 @@ impl From<ble_uuid_any_t> for BleUUID {
     }
 }
 
-impl ToString for BleUUID {
-    fn to_string(&self) -> String {
-        unsafe {
-            match self.ble_uuid {
+impl std::str::FromStr for BleUUID {
+    type Err = BleError;
 
-                ble_uuid_any_t { u16_ } if u16_.u.type_ == BLE_UUID_TYPE_16 as u8 => format!(
-                    "{:04x}", u16_.value,
-                ),
-                ble_uuid_any_t { u128_ } if u128_.u.type_ == BLE_UUID_TYPE_128 as u8 => format!(
-                    "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
-                    u128_.value[15],
-                    u128_.value[14],
-                    u128_.value[13],
-                    u128_.value[12],
-                    u128_.value[11],
-                    u128_.value[10],
-                    u128_.value[9],
-                    u128_.value[8],
-                    u128_.value[7],
-                    u128_.value[6],
-                    u128_.value[5],
-                    u128_.value[4],
-                    u128_.value[3],
-                    u128_.value[2],
-                    u128_.value[1],
-                    u128_.value[0],
-                ),
-                _ => {
-                    panic!("unsupported uuid type");
-                }
-            }
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Self::parse(value)
+    }
+}
+
+impl std::fmt::Display for BleUUID {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.value() {
+            (type_, value) if type_ == BLE_UUID_TYPE_16 as u8 => write!(f, "{:04x}", value),
+            (type_, value) if type_ == BLE_UUID_TYPE_32 as u8 => write!(f, "{:08x}", value),
+            (type_, value) if type_ == BLE_UUID_TYPE_128 as u8 => write!(
+                f,
+                "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+                value >> 96,
+                (value >> 80) & 0xffff,
+                (value >> 64) & 0xffff,
+                (value >> 48) & 0xffff,
+                value & 0xffff_ffff_ffff,
+            ),
+            (type_, _) => write!(f, "<uuid type {}>", type_),
         }
     }
 }
 
+impl std::fmt::Debug for BleUUID {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "BleUUID({})", self)
+    }
+}
+
+// UUIDs of different widths never match, even if they stand for the same
+// one of the Bluetooth base UUID.
 impl PartialEq for BleUUID {
     fn eq(&self, other: &Self) -> bool {
-        unsafe {
-            if self.ble_uuid.u.type_ != other.ble_uuid.u.type_ {
-                return false;
-            }
-            match self.ble_uuid {
-                ble_uuid_any_t { u16_: a } if a.u.type_ == BLE_UUID_TYPE_16 as u8 => {
-                    match other.ble_uuid {
-                        ble_uuid_any_t { u16_: b } => a.value == b.value,
-                    }
-                }
-                ble_uuid_any_t { u128_: a } if a.u.type_ == BLE_UUID_TYPE_128 as u8 => {
-                    match other.ble_uuid {
-                        ble_uuid_any_t { u128_: b } => a.value == b.value,
-                    }
-                }
-                _ => {
-                    panic!("unsupported uuid type");
-                }
-            }
-        }
+        self.value() == other.value()
     }
 }
 
-impl std::fmt::Debug for BleUUID {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("BleUUID").field("ble_uuid", &0).finish()
+impl Eq for BleUUID {}
+
+impl std::hash::Hash for BleUUID {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.value().hash(state);
     }
 }
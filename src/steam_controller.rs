@@ -1,12 +1,9 @@
 use anyhow::Result;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
 
 use crate::{
-    ble::{
-        client::{BleClient, BleConnectEvent},
-        scan::BleScan,
-        uuid::BleUUID,
-        SafeBle,
-    },
+    ble::{client::BleClient, dev::BlePeerDevice, scan::BleScan, uuid::BleUUID, SafeBle},
     get_preference, write_preference,
 };
 
@@ -37,9 +34,9 @@ static STEAM_CONTROLLER_FLAG_LEFT_PAD: u16 = 0x0100;
 static STEAM_CONTROLLER_FLAG_RIGHT_PAD: u16 = 0x0200;
 
 // static HID_UUID: &str = "00001812-0000-1000-8000-00805f9b34fb";
-static SERVICE_UUID: &str = "100f6c32-1735-4313-b402-38567131e5f3";
-static EVENTS_CHR_UUID: &str = "100F6C33-1735-4313-B402-38567131E5F3";
-static STEAM_MODE_CHR_UUID: &str = "100F6C34-1735-4313-B402-38567131E5F3";
+static SERVICE_UUID: BleUUID = BleUUID::from_u128(0x100f6c32_1735_4313_b402_38567131e5f3);
+static EVENTS_CHR_UUID: BleUUID = BleUUID::from_u128(0x100f6c33_1735_4313_b402_38567131e5f3);
+static STEAM_MODE_CHR_UUID: BleUUID = BleUUID::from_u128(0x100f6c34_1735_4313_b402_38567131e5f3);
 static STEAM_MODE_COMMAND: &[u8] = &[0xc0, 0x87, 0x03, 0x08, 0x07, 0x00];
 
 #[derive(Debug)]
@@ -82,6 +79,8 @@ pub enum SteamControllerEvent {
     Disconnected,
 }
 
+// Enabling Ble::set_gatt_store lets reconnects to the bonded controller skip
+// the discovery.
 pub fn connect<F>(ble: SafeBle, mut cb: F) -> Result<()>
 where
     F: FnMut(SteamControllerEvent) + 'static + Send,
@@ -104,9 +103,6 @@ fn inner_loop<F>(ble: SafeBle, cb: &mut F) -> Result<()>
 where
     F: FnMut(SteamControllerEvent) + 'static + Send,
 {
-    let svc_uuid = &BleUUID::parse(SERVICE_UUID)?;
-    let events_chr_uuid = &BleUUID::parse(EVENTS_CHR_UUID)?;
-    let steam_mode_chr_uuid = &BleUUID::parse(STEAM_MODE_CHR_UUID)?;
     let mut client = BleClient::new(ble.clone());
 
     // Find the steam controller device and connect to it.
@@ -151,55 +147,52 @@ where
         dev.conn_handle().unwrap_or(u32::MAX),
     );
 
-    // Search for the ble service that reports controller events.
-    let svc = match dev.get_service_by_uuid(svc_uuid)? {
-        Some(svc) => svc,
-        None => {
-            anyhow::bail!("Service not found on steam controller");
+    let events_rx = setup_controller(&mut dev)?;
+
+    // Wait for steam controller events, decode and forward them to the
+    // callback. The channel is closed when the controller disconnects.
+    let mut prev_buttons: u32 = 0;
+    loop {
+        match events_rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(data) => {
+                for e in decode_steam_controller_packet(data, &mut prev_buttons) {
+                    cb(e);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
         }
-    };
+        // The other connection events aren't needed, but they pile up in the
+        // device's event channel unless drained.
+        dev.use_events_channel(|event_rx| while event_rx.try_recv().is_ok() {})
+            .ok();
+    }
+
+    Ok(())
+}
+
+// Subscribes to the controller events and sets it into steam mode.
+fn setup_controller(dev: &mut BlePeerDevice) -> Result<Receiver<Vec<u8>>> {
+    let gatt = dev.discover_all()?;
 
     // Register for notifications on the events characteristic.
-    let chrs = svc.get_characteristics()?;
-    let events_chr = match chrs.iter().find(|chr| chr.uuid() == events_chr_uuid) {
+    let events_chr = match gatt.characteristic(&SERVICE_UUID, &EVENTS_CHR_UUID) {
         Some(chr) => chr,
         None => {
             anyhow::bail!("Gamepad events charateristic not found on steam controller");
         }
     };
-    events_chr.set_notify(true)?;
+    let events_rx = events_chr.subscribe()?;
 
     // Set the controller into steam mode (faster updates and ???).
-    let steam_mode_chr = match chrs.iter().find(|chr| chr.uuid() == steam_mode_chr_uuid) {
+    let steam_mode_chr = match gatt.characteristic(&SERVICE_UUID, &STEAM_MODE_CHR_UUID) {
         Some(chr) => chr,
         None => {
             anyhow::bail!("Steam mode charateristic not found on steam controller");
         }
     };
-    steam_mode_chr.write(STEAM_MODE_COMMAND)?;
-
-    // Wait for steam controller events, decode and forward them to the
-    // callback.
-    dev.use_events_channel(move |event_rx| {
-        let mut prev_buttons: u32 = 0;
-        loop {
-            match event_rx.recv() {
-                Ok(BleConnectEvent::Notification(data)) => {
-                    for e in decode_steam_controller_packet(data, &mut prev_buttons) {
-                        cb(e);
-                    }
-                }
-                Ok(BleConnectEvent::Disconnected(_)) => break,
-                Ok(_) => {}
-                Err(e) => {
-                    log::error!("Steam controller event channel error: {}", e);
-                    break;
-                }
-            }
-        }
-    });
-
-    Ok(())
+    steam_mode_chr.characteristic.write(STEAM_MODE_COMMAND)?;
+    Ok(events_rx)
 }
 
 // Decode BLE data packet from the Steam Controller and return the corresponding
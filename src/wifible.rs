@@ -42,6 +42,13 @@ pub fn connect_wifi() -> Result<()> {
 }
 
 pub fn connect_ble() -> Result<crate::ble::SafeBle> {
+    connect_ble_with_server(None)
+}
+
+// The GATT server is only used if the BLE stack wasn't initialized yet.
+pub fn connect_ble_with_server(
+    gatt_server: Option<crate::ble::server::BleGattServer>,
+) -> Result<crate::ble::SafeBle> {
     crate::state::with_state(|state| match &state.ble {
         Some(ble) => Ok(ble.clone()),
         None => {
@@ -51,7 +58,7 @@ pub fn connect_ble() -> Result<crate::ble::SafeBle> {
                 }
                 None => {}
             };
-            match crate::ble::Ble::new_no_auto(state.nvs()?) {
+            match crate::ble::Ble::new_with_server(state.nvs()?, gatt_server) {
                 Ok(b) => {
                     state.ble = Some(b.clone());
                     Ok(b)